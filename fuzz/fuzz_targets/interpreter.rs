@@ -0,0 +1,16 @@
+//! Runs arbitrary bytes as a ROM through the interpreter, looking for
+//! panics.
+//!
+//!     cargo +nightly fuzz run interpreter
+//!     cargo +nightly fuzz tmin interpreter artifacts/interpreter/crash-...
+//!
+//! Copy the minimized ROM into fuzz/regressions as a .ch8 once it's fixed,
+//! with the error it should now stop with in `REGRESSIONS` in
+//! src/fuzzing.rs; the test suite runs everything there.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|rom: &[u8]| {
+    let _ = sdl_test::fuzzing::run_rom(rom);
+});
@@ -0,0 +1,1041 @@
+use crate::machine::ChipMemory;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Where assembled programs start.
+const ORIGIN: usize = ChipMemory::ROM_STARTING_MEMORY_LOCATION;
+/// XO-CHIP programs can fill a 64K address space.
+const MAX_ADDRESS: usize = 0x10000;
+/// Guards against macros that expand into themselves forever.
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+/// An assembled program, with what the debugger needs to relate it back to
+/// its source.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    /// The binary, to be loaded at 0x200.
+    pub bytes: Vec<u8>,
+    /// The source line (from 1) each instruction came from, by address.
+    pub source_map: Vec<(u16, usize)>,
+    /// Labels and their addresses, in address order.
+    pub labels: Vec<(String, u16)>,
+    /// Addresses marked with `:breakpoint`, and the names given to them.
+    pub breakpoints: Vec<(String, u16)>,
+}
+
+impl Program {
+    /// The source line the instruction at `address` came from.
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.source_map
+            .binary_search_by_key(&address, |&(a, _)| a)
+            .ok()
+            .map(|i| self.source_map[i].1)
+    }
+    pub fn label_address(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|&(_, address)| address)
+    }
+}
+
+/// Whether `path` names Octo source rather than a binary.
+pub fn is_source(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("8o"))
+}
+
+/// Reads a ROM, assembling it first if it is Octo source.
+pub fn read_rom(path: &Path) -> Result<(Vec<u8>, Option<Program>), String> {
+    if !is_source(path) {
+        let rom = fs::read(path).map_err(|e| format!("Unable to read rom: {}", e))?;
+        return Ok((rom, None));
+    }
+    let source = fs::read_to_string(path).map_err(|e| format!("Unable to read source: {}", e))?;
+    let program = assemble(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((program.bytes.clone(), Some(program)))
+}
+
+/// Assembles Octo source into a CHIP-8, SUPER-CHIP or XO-CHIP binary.
+pub fn assemble(source: &str) -> Result<Program, String> {
+    let mut assembler = Assembler::new(tokenize(source));
+    assembler.run().map_err(|e| match assembler.line {
+        0 => e,
+        line => format!("line {}: {}", line, e),
+    })?;
+    Ok(assembler.finish())
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for word in code.split_whitespace() {
+            tokens.push(Token {
+                text: word.to_string(),
+                line: n + 1,
+            });
+        }
+    }
+    tokens
+}
+
+/// A value that may name a label further on in the source.
+enum Value {
+    Known(i64),
+    Forward(String),
+}
+
+#[derive(Clone, Copy)]
+enum FixupKind {
+    /// The low 12 bits of an instruction.
+    Address,
+    /// A whole byte, holding the label shifted right this far.
+    Byte(u32),
+    /// The top nibble of a byte is kept, the low one gets the label's
+    /// bits 8-11.
+    HighNibble,
+    /// A big-endian 16-bit word.
+    Word,
+}
+
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    name: String,
+    line: usize,
+}
+
+/// How a condition is tested: the instructions that set up VF, if any, and
+/// the skip that follows.
+struct Condition {
+    setup: Vec<u16>,
+    /// Skips when the condition holds.
+    skip_if_true: u16,
+    /// Skips when the condition doesn't hold.
+    skip_if_false: u16,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+enum Block {
+    Loop {
+        start: usize,
+        /// `while` jumps out of the loop, patched at `again`.
+        exits: Vec<usize>,
+    },
+    If {
+        /// The jump taken when the condition fails, or at the end of the
+        /// `then` half when there is an `else`.
+        jump: usize,
+    },
+}
+
+struct Assembler {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Line of the statement being assembled, for errors and the source map.
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    macro_calls: usize,
+    blocks: Vec<Block>,
+    fixups: Vec<Fixup>,
+    source_map: Vec<(u16, usize)>,
+    breakpoints: Vec<(String, u16)>,
+}
+
+impl Assembler {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            line: 0,
+            rom: Vec::new(),
+            here: ORIGIN,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            macro_calls: 0,
+            blocks: Vec::new(),
+            fixups: Vec::new(),
+            source_map: Vec::new(),
+            breakpoints: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        while self.pos < self.tokens.len() {
+            self.line = self.tokens[self.pos].line;
+            self.statement()?;
+        }
+        if let Some(block) = self.blocks.last() {
+            return Err(match block {
+                Block::Loop { .. } => "loop without again".to_string(),
+                Block::If { .. } => "begin without end".to_string(),
+            });
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let address = *self
+                .labels
+                .get(&fixup.name)
+                .ok_or_else(|| format!("undefined name {}", fixup.name))?
+                as usize;
+            let at = fixup.address - ORIGIN;
+            match fixup.kind {
+                FixupKind::Address => {
+                    if address > 0xFFF {
+                        return Err(format!("{} is out of reach at {:#x}", fixup.name, address));
+                    }
+                    self.rom[at] |= (address >> 8) as u8;
+                    self.rom[at + 1] = address as u8;
+                }
+                FixupKind::Byte(shift) => self.rom[at] = (address >> shift) as u8,
+                FixupKind::HighNibble => self.rom[at] |= (address >> 8) as u8 & 0xF,
+                FixupKind::Word => {
+                    self.rom[at] = (address >> 8) as u8;
+                    self.rom[at + 1] = address as u8;
+                }
+            }
+        }
+        self.line = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Program {
+        self.source_map.sort();
+        self.source_map.dedup_by_key(|&mut (address, _)| address);
+        let mut labels: Vec<_> = self.labels.into_iter().collect();
+        labels.sort_by_key(|&(ref name, address)| (address, name.clone()));
+        Program {
+            bytes: self.rom,
+            source_map: self.source_map,
+            labels,
+            breakpoints: self.breakpoints,
+        }
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or("unexpected end of source")?;
+        self.pos += 1;
+        Ok(token.text.clone())
+    }
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.text.as_str())
+    }
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != word {
+            return Err(format!("expected {}, found {}", word, token));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= MAX_ADDRESS {
+            return Err("program is too large".to_string());
+        }
+        let at = self.here - ORIGIN;
+        if at >= self.rom.len() {
+            self.rom.resize(at + 1, 0);
+        }
+        self.rom[at] = byte;
+        self.here += 1;
+        Ok(())
+    }
+    fn emit(&mut self, op: u16) -> Result<(), String> {
+        self.source_map.push((self.here as u16, self.line));
+        self.emit_byte((op >> 8) as u8)?;
+        self.emit_byte(op as u8)
+    }
+    /// Emits an instruction taking a 12-bit address.
+    fn emit_address(&mut self, op: u16, value: Value) -> Result<(), String> {
+        match value {
+            Value::Known(address) => {
+                if !(0..=0xFFF).contains(&address) {
+                    return Err(format!("address {:#x} is out of range", address));
+                }
+                self.emit(op | address as u16)
+            }
+            Value::Forward(name) => {
+                self.fixup(self.here, FixupKind::Address, name);
+                self.emit(op)
+            }
+        }
+    }
+    fn fixup(&mut self, address: usize, kind: FixupKind, name: String) {
+        self.fixups.push(Fixup {
+            address,
+            kind,
+            name,
+            line: self.line,
+        });
+    }
+    /// Writes a jump to `target` over the placeholder at `at`.
+    fn patch_jump(&mut self, at: usize, target: usize) -> Result<(), String> {
+        if target > 0xFFF {
+            return Err(format!("jump target {:#x} is out of reach", target));
+        }
+        let op = 0x1000 | target as u16;
+        self.rom[at - ORIGIN] = (op >> 8) as u8;
+        self.rom[at - ORIGIN + 1] = op as u8;
+        Ok(())
+    }
+
+    fn register(&self, token: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(token) {
+            return Some(register);
+        }
+        let digit = token.strip_prefix(['v', 'V'])?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+    fn expect_register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.register(&token)
+            .ok_or_else(|| format!("expected a register, found {}", token))
+    }
+
+    /// A number, constant or label that is already known.
+    fn known(&self, token: &str) -> Option<i64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).map(|&c| c as i64))
+            .or_else(|| self.labels.get(token).map(|&a| a as i64))
+    }
+    fn value(&mut self) -> Result<Value, String> {
+        let token = self.next()?;
+        if let Some(value) = self.known(&token) {
+            return Ok(Value::Known(value));
+        }
+        if is_name(&token) && self.register(&token).is_none() {
+            return Ok(Value::Forward(token));
+        }
+        Err(format!("expected a value, found {}", token))
+    }
+    fn byte(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        let value = self
+            .known(&token)
+            .ok_or_else(|| format!("expected a number, found {}", token))?;
+        if !(-128..=255).contains(&value) {
+            return Err(format!("{} doesn't fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+    fn nibble(&mut self) -> Result<u16, String> {
+        let token = self.next()?;
+        match self.known(&token) {
+            Some(value @ 0..=15) => Ok(value as u16),
+            _ => Err(format!("expected a number from 0 to 15, found {}", token)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // Names the second byte of the next instruction, for code
+                // that rewrites its own operands
+                let name = self.next()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name, register);
+            }
+            ":const" => {
+                let name = self.next()?;
+                let token = self.next()?;
+                let value = self
+                    .known(&token)
+                    .ok_or_else(|| format!("expected a number, found {}", token))?;
+                self.constants.insert(name, value as f64);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.braced_expression()?;
+                self.constants.insert(name, value);
+            }
+            ":org" => {
+                let address = match self.peek() {
+                    Some("{") => self.braced_expression()? as i64,
+                    _ => {
+                        let token = self.next()?;
+                        self.known(&token)
+                            .ok_or_else(|| format!("expected an address, found {}", token))?
+                    }
+                };
+                if !(ORIGIN as i64..MAX_ADDRESS as i64).contains(&address) {
+                    return Err(format!("can't assemble at {:#x}", address));
+                }
+                self.here = address as usize;
+            }
+            ":byte" => {
+                if self.peek() == Some("{") {
+                    let value = self.braced_expression()?;
+                    self.emit_byte(value as i64 as u8)?;
+                } else {
+                    match self.value()? {
+                        Value::Known(value) => self.emit_byte(value as u8)?,
+                        Value::Forward(name) => {
+                            self.fixup(self.here, FixupKind::Byte(0), name);
+                            self.emit_byte(0)?;
+                        }
+                    }
+                }
+            }
+            ":pointer" => match self.value()? {
+                Value::Known(value) => {
+                    self.emit_byte((value >> 8) as u8)?;
+                    self.emit_byte(value as u8)?;
+                }
+                Value::Forward(name) => {
+                    self.fixup(self.here, FixupKind::Word, name);
+                    self.emit_byte(0)?;
+                    self.emit_byte(0)?;
+                }
+            },
+            ":unpack" => {
+                // v0 and v1 get the address, with a nibble in v0's top half
+                // or the whole high byte for `long`
+                let long = self.peek() == Some("long");
+                let high = if long {
+                    self.pos += 1;
+                    0
+                } else {
+                    self.nibble()? << 4
+                };
+                let value = self.value()?;
+                match value {
+                    Value::Known(address) => {
+                        let top = if long {
+                            (address >> 8) as u16 & 0xFF
+                        } else {
+                            high | ((address >> 8) as u16 & 0xF)
+                        };
+                        self.emit(0x6000 | top)?;
+                        self.emit(0x6100 | (address as u16 & 0xFF))?;
+                    }
+                    Value::Forward(name) => {
+                        let kind = if long {
+                            FixupKind::Byte(8)
+                        } else {
+                            FixupKind::HighNibble
+                        };
+                        self.fixup(self.here + 1, kind, name.clone());
+                        self.emit(0x6000 | high)?;
+                        self.fixup(self.here + 1, FixupKind::Byte(0), name);
+                        self.emit(0x6100)?;
+                    }
+                }
+            }
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                let name = self.next()?;
+                self.breakpoints.push((name, self.here as u16));
+            }
+            ":monitor" => {
+                // Only meaningful to Octo's own debugger
+                self.next()?;
+                self.next()?;
+            }
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                for op in condition.setup {
+                    self.emit(op)?;
+                }
+                self.emit(condition.skip_if_true)?;
+                let exit = self.here;
+                self.emit(0x1000)?;
+                match self.blocks.iter_mut().rev().find_map(|b| match b {
+                    Block::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(exit),
+                    None => return Err("while outside a loop".to_string()),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    self.emit_address(0x1000, Value::Known(start as i64))?;
+                    for exit in exits {
+                        self.patch_jump(exit, self.here)?;
+                    }
+                }
+                _ => return Err("again without loop".to_string()),
+            },
+            "if" => {
+                let condition = self.condition()?;
+                for op in condition.setup {
+                    self.emit(op)?;
+                }
+                match self.next()?.as_str() {
+                    "then" => self.emit(condition.skip_if_false)?,
+                    "begin" => {
+                        self.emit(condition.skip_if_true)?;
+                        self.blocks.push(Block::If { jump: self.here });
+                        self.emit(0x1000)?;
+                    }
+                    other => return Err(format!("expected then or begin, found {}", other)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump }) => {
+                    let end_jump = self.here;
+                    self.emit(0x1000)?;
+                    self.patch_jump(jump, self.here)?;
+                    self.blocks.push(Block::If { jump: end_jump });
+                }
+                _ => return Err("else without begin".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump }) => self.patch_jump(jump, self.here)?,
+                _ => return Err("end without begin".to_string()),
+            },
+            "clear" => self.emit(0x00E0)?,
+            "return" | ";" => self.emit(0x00EE)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00C0 | n)?
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00D0 | n)?
+            }
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "audio" => self.emit(0xF002)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xF001 | n << 8)?
+            }
+            "jump" => {
+                let target = self.value()?;
+                self.emit_address(0x1000, target)?
+            }
+            "jump0" => {
+                let target = self.value()?;
+                self.emit_address(0xB000, target)?
+            }
+            ":call" => {
+                let target = self.value()?;
+                self.emit_address(0x2000, target)?
+            }
+            "native" => {
+                let target = self.value()?;
+                self.emit_address(0x0000, target)?
+            }
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let n = self.nibble()?;
+                self.emit(0xD000 | x << 8 | y << 4 | n)?
+            }
+            "save" | "load" => {
+                let x = self.expect_register()? as u16;
+                let (single, range) = if token == "save" {
+                    (0xF055, 0x5002)
+                } else {
+                    (0xF065, 0x5003)
+                };
+                if self.peek() == Some("-") {
+                    self.pos += 1;
+                    let y = self.expect_register()? as u16;
+                    self.emit(range | x << 8 | y << 4)?
+                } else {
+                    self.emit(single | x << 8)?
+                }
+            }
+            "bcd" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF033 | x << 8)?
+            }
+            "saveflags" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF075 | x << 8)?
+            }
+            "loadflags" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF085 | x << 8)?
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                let op = match token.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit(op | x << 8)?
+            }
+            "i" => self.index_statement()?,
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    self.register_statement(x as u16)?;
+                } else if self.macros.contains_key(&token) {
+                    self.expand_macro(&token)?;
+                } else if let Some(value) = self.known(&token).filter(|_| !is_name(&token)) {
+                    // Bare numbers are data
+                    self.emit_byte(value as u8)?;
+                } else if let Some(&address) = self.labels.get(&token) {
+                    self.emit_address(0x2000, Value::Known(address as i64))?;
+                } else if is_name(&token) && !self.constants.contains_key(&token) {
+                    // A bare label is a subroutine call
+                    self.fixup(self.here, FixupKind::Address, token);
+                    self.emit(0x2000)?;
+                } else {
+                    return Err(format!("unexpected {}", token));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), String> {
+        if !is_name(&name) || self.register(&name).is_some() {
+            return Err(format!("{} can't be used as a label", name));
+        }
+        if self.labels.insert(name.clone(), address as u16).is_some() {
+            return Err(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), String> {
+        match self.next()?.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.pos += 1;
+                    let x = self.expect_register()? as u16;
+                    self.emit(0xF029 | x << 8)
+                }
+                Some("bighex") => {
+                    self.pos += 1;
+                    let x = self.expect_register()? as u16;
+                    self.emit(0xF030 | x << 8)
+                }
+                Some("long") => {
+                    self.pos += 1;
+                    let value = self.value()?;
+                    self.emit(0xF000)?;
+                    match value {
+                        Value::Known(address) => self.emit(address as u16),
+                        Value::Forward(name) => {
+                            self.fixup(self.here, FixupKind::Word, name);
+                            self.emit(0x0000)
+                        }
+                    }
+                }
+                _ => {
+                    let value = self.value()?;
+                    self.emit_address(0xA000, value)
+                }
+            },
+            "+=" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF01E | x << 8)
+            }
+            other => Err(format!("unexpected {} after i", other)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u16) -> Result<(), String> {
+        let op = self.next()?;
+        let operand = self.peek().unwrap_or("").to_string();
+        if let Some(y) = self.register(&operand) {
+            self.pos += 1;
+            let y = y as u16;
+            let code = match op.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => return Err(format!("unexpected {} between registers", op)),
+            };
+            return self.emit(0x8000 | x << 8 | y << 4 | code);
+        }
+        match (op.as_str(), operand.as_str()) {
+            (":=", "random") => {
+                self.pos += 1;
+                let mask = self.byte()? as u16;
+                self.emit(0xC000 | x << 8 | mask)
+            }
+            (":=", "delay") => {
+                self.pos += 1;
+                self.emit(0xF007 | x << 8)
+            }
+            (":=", "key") => {
+                self.pos += 1;
+                self.emit(0xF00A | x << 8)
+            }
+            (":=", _) => {
+                let n = self.byte()? as u16;
+                self.emit(0x6000 | x << 8 | n)
+            }
+            ("+=", _) => {
+                let n = self.byte()? as u16;
+                self.emit(0x7000 | x << 8 | n)
+            }
+            ("-=", _) => {
+                let n = self.byte()?.wrapping_neg() as u16;
+                self.emit(0x7000 | x << 8 | n)
+            }
+            _ => Err(format!("unexpected {} {}", op, operand)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.expect_register()? as u16;
+        let op = self.next()?;
+        let simple = |skip_if_true, skip_if_false| Condition {
+            setup: Vec::new(),
+            skip_if_true,
+            skip_if_false,
+        };
+        match op.as_str() {
+            "key" => return Ok(simple(0xE09E | x << 8, 0xE0A1 | x << 8)),
+            "-key" => return Ok(simple(0xE0A1 | x << 8, 0xE09E | x << 8)),
+            _ => {}
+        }
+        let operand = self.next()?;
+        let operand = match self.register(&operand) {
+            Some(y) => Ok(y as u16),
+            None => self
+                .known(&operand)
+                .filter(|n| (-128..=255).contains(n))
+                .map(|n| n as u8 as u16)
+                .ok_or_else(|| format!("expected a register or byte, found {}", operand)),
+        }?;
+        let is_register = self.register(&self.tokens[self.pos - 1].text).is_some();
+        Ok(match (op.as_str(), is_register) {
+            ("==", false) => simple(0x3000 | x << 8 | operand, 0x4000 | x << 8 | operand),
+            ("!=", false) => simple(0x4000 | x << 8 | operand, 0x3000 | x << 8 | operand),
+            ("==", true) => simple(
+                0x5000 | x << 8 | operand << 4,
+                0x9000 | x << 8 | operand << 4,
+            ),
+            ("!=", true) => simple(
+                0x9000 | x << 8 | operand << 4,
+                0x5000 | x << 8 | operand << 4,
+            ),
+            ("<" | ">" | "<=" | ">=", _) => {
+                // VF gets the other side, then one is taken from the other
+                // so the borrow flag answers the comparison
+                let load = if is_register {
+                    0x8F00 | operand << 4
+                } else {
+                    0x6F00 | operand
+                };
+                let subtract = match op.as_str() {
+                    // VF = VX - VF, flag set when VX >= the operand
+                    "<" | ">=" => 0x8F07 | x << 4,
+                    // VF = VF - VX, flag set when the operand >= VX
+                    _ => 0x8F05 | x << 4,
+                };
+                // Less-than and greater-than hold when the flag is clear
+                let (if_true, if_false) = match op.as_str() {
+                    "<" | ">" => (0x3F00, 0x4F00),
+                    _ => (0x4F00, 0x3F00),
+                };
+                Condition {
+                    setup: vec![load, subtract],
+                    skip_if_true: if_true,
+                    skip_if_false: if_false,
+                }
+            }
+            _ => return Err(format!("unknown comparison {}", op)),
+        })
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            args.push(token);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self
+                .tokens
+                .get(self.pos)
+                .cloned()
+                .ok_or("macro without a closing }")?;
+            self.pos += 1;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        self.macro_calls += 1;
+        if self.macro_calls > MAX_MACRO_EXPANSIONS {
+            return Err("too many macro expansions".to_string());
+        }
+        let count = self.macros[name].args.len();
+        let mut values = Vec::new();
+        for _ in 0..count {
+            values.push(self.next()?);
+        }
+        let calls = (self.macro_calls - 1).to_string();
+        let line = self.line;
+        let expansion: Vec<Token> = self.macros[name]
+            .body
+            .iter()
+            .map(|token| {
+                let text = match self.macros[name].args.iter().position(|a| *a == token.text) {
+                    Some(i) => values[i].clone(),
+                    None if token.text == "CALLS" => calls.clone(),
+                    None => token.text.clone(),
+                };
+                // Expanded code maps back to where the macro was used
+                Token { text, line }
+            })
+            .collect();
+        self.tokens.splice(self.pos..self.pos, expansion);
+        Ok(())
+    }
+
+    /// Evaluates `{ expression }`.  As in Octo, operators have no
+    /// precedence and are applied right to left.
+    fn braced_expression(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        let value = self.expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+    fn expression(&mut self) -> Result<f64, String> {
+        let lhs = self.term()?;
+        let op = self.peek().unwrap_or("").to_string();
+        let apply: fn(f64, f64) -> f64 = match op.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| ((a as i64) << (b as i64)) as f64,
+            ">>" => |a, b| ((a as i64) >> (b as i64)) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as i64 as f64,
+            ">" => |a, b| (a > b) as i64 as f64,
+            "<=" => |a, b| (a <= b) as i64 as f64,
+            ">=" => |a, b| (a >= b) as i64 as f64,
+            "==" => |a, b| (a == b) as i64 as f64,
+            "!=" => |a, b| (a != b) as i64 as f64,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+        let rhs = self.expression()?;
+        Ok(apply(lhs, rhs))
+    }
+    fn term(&mut self) -> Result<f64, String> {
+        let token = self.next()?;
+        let unary: fn(f64) -> f64 = match token.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                return Ok(value);
+            }
+            "-" => |a| -a,
+            "~" => |a| !(a as i64) as f64,
+            "!" => |a| (a == 0.0) as i64 as f64,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "sign" => f64::signum,
+            "ceil" => f64::ceil,
+            "floor" => f64::floor,
+            "@" => {
+                let address = self.term()? as usize;
+                let byte = address
+                    .checked_sub(ORIGIN)
+                    .and_then(|at| self.rom.get(at))
+                    .copied()
+                    .unwrap_or(0);
+                return Ok(byte as f64);
+            }
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            _ => {
+                return match self.constants.get(&token) {
+                    Some(&value) => Ok(value),
+                    None => self
+                        .known(&token)
+                        .map(|v| v as f64)
+                        .ok_or_else(|| format!("unknown name {} in expression", token)),
+                }
+            }
+        };
+        Ok(unary(self.term()?))
+    }
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.bytes().all(|b| b.is_ascii_digit()) && !digits.is_empty() {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Whether `token` could name a label, constant or macro.
+fn is_name(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_assemble() {
+        let program = assemble(
+            "
+            :alias counter v3
+            :const SPEED 2
+            : main
+              clear
+              counter := 0
+              loop
+                counter += SPEED
+                if counter == 10 then counter := 0
+                while counter != 8
+                draw
+              again
+              if v1 key begin
+                v2 := 1
+              else
+                v2 := 2
+              end
+            : draw
+              i := sprite
+              sprite v0 v1 1
+              ;
+            : sprite
+              0xFF
+            ",
+        )
+        .unwrap();
+        let words: Vec<u16> = program
+            .bytes
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                0x00E0, 0x6300, // clear, counter := 0
+                0x7302, 0x430A, 0x6300, // loop: +=, if ... then
+                0x4308, 0x1212, // while counter != 8
+                0x221C, 0x1204, // draw, again
+                0xE19E, 0x121A, 0x6201, 0x121C, 0x6202, // if begin else end
+                0xA222, 0xD011, 0x00EE, // draw
+                0xFF00,
+            ]
+        );
+        assert_eq!(program.label_address("draw"), Some(0x21C));
+        assert_eq!(program.line_at(0x21C), Some(19));
+        // Past 0xFFF neither a loop nor a call to an earlier label can reach
+        assert!(assemble(":org 0x1000 : main loop again").is_err());
+        assert!(assemble(":org 0x1000 : sub ; : main sub").is_err());
+    }
+
+    #[test]
+    fn test_calc_and_macros() {
+        let program = assemble(
+            "
+            :calc WIDTH { 8 * 2 + 1 }
+            :macro set reg value { reg := value }
+            set v0 WIDTH
+            :org 0x208
+            :byte { WIDTH - 1 }
+            :unpack 0xA target
+            : target
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            [0x60, 24, 0, 0, 0, 0, 0, 0, 23, 0x60, 0xA2, 0x61, 0x0D]
+        );
+        assert!(assemble("jump nowhere").is_err());
+        assert!(assemble("loop v0 += 1").is_err());
+    }
+}
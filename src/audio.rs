@@ -0,0 +1,594 @@
+use crate::wav::WavWriter;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Length of the fade in and out applied whenever the beeper starts or
+/// stops, which keeps it from clicking.
+const RAMP_SECONDS: f32 = 0.005;
+const WAV_SAMPLE_RATE: u32 = 44100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub fn next(self) -> Self {
+        match self {
+            Waveform::Square => Waveform::Sine,
+            Waveform::Sine => Waveform::Triangle,
+            Waveform::Triangle => Waveform::Noise,
+            Waveform::Noise => Waveform::Square,
+        }
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Unknown waveform {}", s)),
+        }
+    }
+}
+
+/// What the beeper sounds like.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeepConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    /// Fraction of each period the square wave spends high.
+    pub duty: f32,
+    pub volume: f32,
+}
+
+impl Default for BeepConfig {
+    fn default() -> Self {
+        Self {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            duty: 0.5,
+            volume: 0.1,
+        }
+    }
+}
+
+impl BeepConfig {
+    /// Moves the tone by `semitones`, keeping it within hearing.
+    pub fn transpose(&mut self, semitones: i32) {
+        let frequency = self.frequency * 2f32.powf(semitones as f32 / 12.0);
+        self.frequency = frequency.clamp(20.0, 20000.0);
+    }
+    pub fn adjust_duty(&mut self, by: f32) {
+        self.duty = (self.duty + by).clamp(0.0, 1.0);
+    }
+    pub fn adjust_volume(&mut self, by: f32) {
+        self.volume = (self.volume + by).clamp(0.0, 1.0);
+    }
+}
+
+/// An XO-CHIP audio pattern: 128 one-bit samples, most significant bit
+/// first, looped at a rate set by the pitch register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioPattern {
+    pub bits: [u8; 16],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    /// The pitch register's value at reset, which plays at 4000 Hz.
+    pub const DEFAULT_PITCH: u8 = 64;
+    pub const LENGTH: usize = 128;
+
+    /// How many of the pattern's bits are played per second.
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+    fn bit(&self, n: usize) -> bool {
+        (self.bits[n / 8] << (n % 8)) & 0b1000_0000 != 0
+    }
+}
+
+/// The beeper turning on or off at a point in emulated time, in seconds
+/// since the machine started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoundEvent {
+    pub time: f64,
+    pub on: bool,
+}
+
+/// Everything that happened to the beeper since the last update.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundUpdate {
+    pub events: Vec<SoundEvent>,
+    pub pattern: Option<AudioPattern>,
+    /// How far emulated time has got.
+    pub time: f64,
+}
+
+/// Somewhere the beeper can be played.
+pub trait AudioBackend {
+    /// Schedules the beeper to turn on or off at the event's time.
+    fn push_event(&mut self, event: SoundEvent);
+    /// Lets the backend know emulated time has reached `time`.
+    fn advance_to(&mut self, _time: f64) {}
+    /// Whether the beeper is on as of the last event pushed.
+    fn playing(&self) -> bool;
+    fn config(&mut self) -> BeepConfig;
+    fn set_config(&mut self, config: BeepConfig);
+    /// Whether the beeper is kept quiet.  Muting only silences what is
+    /// played live; beeps rendered to a file are always kept.
+    fn muted(&mut self) -> bool;
+    fn set_muted(&mut self, muted: bool);
+    /// Plays `pattern` instead of the configured waveform while the beeper is
+    /// on, or goes back to the waveform for `None`.
+    fn set_pattern(&mut self, _pattern: Option<AudioPattern>) {}
+    /// Whether a beep can actually be heard.  Frontends show a visual
+    /// indicator instead when it can't.
+    fn audible(&mut self) -> bool {
+        !self.muted()
+    }
+    /// Called when the session ends.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    fn apply(&mut self, update: &SoundUpdate) {
+        self.set_pattern(update.pattern);
+        for &event in &update.events {
+            self.push_event(event);
+        }
+        self.advance_to(update.time);
+    }
+}
+
+/// Swallows the beeper, for machines without a sound device.
+pub struct NullAudio {
+    playing: bool,
+    config: BeepConfig,
+    muted: bool,
+}
+
+impl NullAudio {
+    pub fn new(config: BeepConfig) -> Self {
+        Self {
+            playing: false,
+            config,
+            muted: false,
+        }
+    }
+}
+
+/// Opens a sound device with `open`, falling back to silence (and the
+/// visual beep indicator) when the beeper is muted or there is no device to
+/// play on.
+pub fn open_device<F>(config: BeepConfig, muted: bool, open: F) -> Box<dyn AudioBackend>
+where
+    F: FnOnce(BeepConfig) -> Result<Box<dyn AudioBackend>, String>,
+{
+    if muted {
+        let mut audio = NullAudio::new(config);
+        audio.set_muted(true);
+        return Box::new(audio);
+    }
+    open(config).unwrap_or_else(|e| {
+        println!("Unable to open an audio device, beeping visually: {}", e);
+        Box::new(NullAudio::new(config))
+    })
+}
+
+impl AudioBackend for NullAudio {
+    fn push_event(&mut self, event: SoundEvent) {
+        self.playing = event.on;
+    }
+    fn playing(&self) -> bool {
+        self.playing
+    }
+    fn config(&mut self) -> BeepConfig {
+        self.config
+    }
+    fn set_config(&mut self, config: BeepConfig) {
+        self.config = config;
+    }
+    fn muted(&mut self) -> bool {
+        self.muted
+    }
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+    fn audible(&mut self) -> bool {
+        false
+    }
+}
+
+/// A tone generator that switches its gate on and off as sound events come
+/// due, so beeps start and stop on the sample their emulated time falls on.
+pub struct ScheduledTone {
+    pub tone: ToneGenerator,
+    events: VecDeque<SoundEvent>,
+    /// The state of the gate once every queued event has been played.
+    last_state: bool,
+}
+
+impl ScheduledTone {
+    pub fn new(tone: ToneGenerator) -> Self {
+        Self {
+            tone,
+            events: VecDeque::new(),
+            last_state: false,
+        }
+    }
+    pub fn push_event(&mut self, event: SoundEvent) {
+        self.last_state = event.on;
+        self.events.push_back(event);
+    }
+    pub fn playing(&self) -> bool {
+        self.last_state
+    }
+    /// Produces the sample that plays at emulated time `time`.
+    pub fn sample_at(&mut self, time: f64) -> f32 {
+        while let Some(event) = self.events.front() {
+            if event.time > time {
+                break;
+            }
+            self.tone.set_gate(event.on);
+            self.events.pop_front();
+        }
+        self.tone.next_sample()
+    }
+}
+
+/// Renders the beeper into a WAV file in step with emulated time, so the
+/// same ROM produces the same audio on every run whatever the host is doing.
+pub struct WavSink {
+    path: PathBuf,
+    tone: ScheduledTone,
+    wav: WavWriter,
+    muted: bool,
+}
+
+impl WavSink {
+    pub fn new(path: &Path, config: BeepConfig) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            tone: ScheduledTone::new(ToneGenerator::new(config, WAV_SAMPLE_RATE)),
+            wav: WavWriter::new(WAV_SAMPLE_RATE),
+            muted: false,
+        }
+    }
+    pub fn wav(&self) -> &WavWriter {
+        &self.wav
+    }
+}
+
+impl AudioBackend for WavSink {
+    fn push_event(&mut self, event: SoundEvent) {
+        self.tone.push_event(event);
+    }
+    fn advance_to(&mut self, time: f64) {
+        let end = (time * WAV_SAMPLE_RATE as f64).round() as usize;
+        while self.wav.len() < end {
+            let sample_time = self.wav.len() as f64 / WAV_SAMPLE_RATE as f64;
+            let sample = self.tone.sample_at(sample_time);
+            self.wav.push(sample);
+        }
+    }
+    fn playing(&self) -> bool {
+        self.tone.playing()
+    }
+    fn config(&mut self) -> BeepConfig {
+        self.tone.tone.config()
+    }
+    fn set_config(&mut self, config: BeepConfig) {
+        self.tone.tone.set_config(config);
+    }
+    fn muted(&mut self) -> bool {
+        self.muted
+    }
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.tone.tone.set_pattern(pattern);
+    }
+    fn finish(&mut self) -> Result<(), String> {
+        self.wav.save(&self.path)?;
+        println!(
+            "Saved {} samples to {}",
+            self.wav.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Produces the beeper's samples.  The output is gated on and off rather
+/// than started and stopped so the envelope can ramp between the two.
+pub struct ToneGenerator {
+    config: BeepConfig,
+    sample_rate: u32,
+    phase_state: f32,
+    gate: bool,
+    envelope: f32,
+    noise_state: u16,
+    noise_value: f32,
+    pattern: Option<AudioPattern>,
+    /// Position within the pattern, in bits.
+    pattern_pos: f32,
+    muted: bool,
+}
+
+impl ToneGenerator {
+    pub fn new(config: BeepConfig, sample_rate: u32) -> Self {
+        Self {
+            config,
+            sample_rate,
+            phase_state: 0.0,
+            gate: false,
+            envelope: 0.0,
+            noise_state: 0xACE1,
+            noise_value: 1.0,
+            pattern: None,
+            pattern_pos: 0.0,
+            muted: false,
+        }
+    }
+    pub fn config(&self) -> BeepConfig {
+        self.config
+    }
+    pub fn set_config(&mut self, config: BeepConfig) {
+        self.config = config;
+    }
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    pub fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.pattern = pattern;
+    }
+    pub fn set_gate(&mut self, gate: bool) {
+        self.gate = gate;
+    }
+    pub fn gate(&self) -> bool {
+        self.gate
+    }
+    pub fn next_sample(&mut self) -> f32 {
+        let ramp_step = 1.0 / (RAMP_SECONDS * self.sample_rate as f32);
+        self.envelope = if self.gate {
+            (self.envelope + ramp_step).min(1.0)
+        } else {
+            (self.envelope - ramp_step).max(0.0)
+        };
+        if self.envelope == 0.0 {
+            return 0.0;
+        }
+
+        let wave = match self.pattern {
+            Some(pattern) => self.next_pattern_level(pattern),
+            None => self.next_waveform_level(),
+        };
+
+        if self.muted {
+            0.0
+        } else {
+            wave * self.config.volume * self.envelope
+        }
+    }
+    /// Steps through the XO-CHIP pattern, resampling it to the output rate
+    /// by taking whichever bit is current at each sample.
+    fn next_pattern_level(&mut self, pattern: AudioPattern) -> f32 {
+        let level = if pattern.bit(self.pattern_pos as usize) {
+            1.0
+        } else {
+            -1.0
+        };
+        self.pattern_pos += pattern.playback_rate() / self.sample_rate as f32;
+        self.pattern_pos %= AudioPattern::LENGTH as f32;
+        level
+    }
+    fn next_waveform_level(&mut self) -> f32 {
+        let phase = self.phase_state;
+        let wave = match self.config.waveform {
+            Waveform::Square => {
+                if phase < self.config.duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (phase * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Noise => self.noise_value,
+        };
+        let phase_inc = self.config.frequency / self.sample_rate as f32;
+        self.phase_state += phase_inc;
+        if self.phase_state >= 1.0 {
+            self.phase_state %= 1.0;
+            self.step_noise();
+        }
+        wave
+    }
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+    }
+    /// Picks the next noise level with a 16-bit Galois LFSR, once per period.
+    fn step_noise(&mut self) {
+        let bit = self.noise_state & 1;
+        self.noise_state >>= 1;
+        if bit == 1 {
+            self.noise_state ^= 0xB400;
+        }
+        self.noise_value = if bit == 1 { 1.0 } else { -1.0 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::{ChipMachine, ChipMemory};
+    #[test]
+    fn test_ramp() {
+        let mut tone = ToneGenerator::new(BeepConfig::default(), 1000);
+        assert_eq!(tone.next_sample(), 0.0);
+        tone.set_gate(true);
+        // The first sample is only a fifth of the way up the 5 ms ramp
+        let first = tone.next_sample();
+        assert!(first.abs() < 0.1 * 0.25);
+        for _ in 0..5 {
+            tone.next_sample();
+        }
+        tone.set_gate(false);
+        let mut samples = [1.0f32; 6];
+        tone.fill(&mut samples);
+        assert!(samples[0] != 0.0);
+        assert_eq!(samples[5], 0.0);
+    }
+    #[test]
+    fn test_mute() {
+        let mut tone = ToneGenerator::new(BeepConfig::default(), 1000);
+        tone.set_muted(true);
+        tone.set_gate(true);
+        let mut samples = [1.0f32; 16];
+        tone.fill(&mut samples);
+        assert!(samples.iter().all(|&s| s == 0.0));
+
+        // Muting the beeper leaves what is rendered to a file alone
+        let mut sink = WavSink::new(Path::new("unused.wav"), BeepConfig::default());
+        sink.set_muted(true);
+        assert!(!sink.audible());
+        sink.push_event(SoundEvent {
+            time: 0.0,
+            on: true,
+        });
+        sink.advance_to(0.01);
+        assert!(sink.wav().samples().iter().any(|&s| s != 0));
+    }
+    #[test]
+    fn test_adjust_beep() {
+        let mut config = BeepConfig::default();
+        config.transpose(12);
+        assert!((config.frequency - 880.0).abs() < 0.01);
+        config.transpose(-24);
+        assert!((config.frequency - 220.0).abs() < 0.01);
+        config.transpose(-1000);
+        assert_eq!(config.frequency, 20.0);
+        config.adjust_duty(-0.25);
+        assert_eq!(config.duty, 0.25);
+        config.adjust_volume(2.0);
+        assert_eq!(config.volume, 1.0);
+        config.adjust_volume(-2.0);
+        assert_eq!(config.volume, 0.0);
+    }
+    #[test]
+    fn test_null_audio() {
+        let mut audio = NullAudio::new(BeepConfig::default());
+        assert!(!audio.playing() && !audio.audible());
+        audio.push_event(SoundEvent {
+            time: 0.0,
+            on: true,
+        });
+        assert!(audio.playing());
+        // Keeps track of settings, for when a device shows up
+        audio.set_muted(true);
+        assert!(audio.muted());
+        let config = BeepConfig {
+            waveform: Waveform::Sine,
+            ..BeepConfig::default()
+        };
+        audio.set_config(config);
+        assert_eq!(audio.config(), config);
+    }
+    #[test]
+    fn test_open_device() {
+        let config = BeepConfig::default();
+        let device = |_| Ok(Box::new(WavSink::new(Path::new("unused.wav"), config)) as _);
+        assert!(open_device(config, false, device).audible());
+        // Beeping visually without a device
+        let mut audio = open_device(config, false, |_| Err("no device".to_string()));
+        assert!(!audio.audible() && !audio.muted());
+        assert_eq!(audio.config(), config);
+        // Muted, no device is opened at all
+        let mut audio = open_device(config, true, |_| panic!("opened a device"));
+        assert!(!audio.audible() && audio.muted());
+    }
+    /// Runs `rom` for a second of emulated time, rendering the beeper.
+    fn render_beeper(rom: &[u8]) -> Vec<i16> {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        machine.load_rom_bytes(rom).unwrap();
+        let mut sink = WavSink::new(Path::new("unused.wav"), BeepConfig::default());
+        for _ in 0..60 {
+            machine.run_cycles(machine.cycles_per_frame).unwrap();
+            machine.tick_timers();
+            sink.apply(&machine.take_sound_update());
+        }
+        sink.wav().samples().to_vec()
+    }
+    /// Index of the last sample the beeper was gated on for.
+    fn beep_end(samples: &[i16]) -> usize {
+        let ramp = (RAMP_SECONDS * WAV_SAMPLE_RATE as f32) as usize;
+        let last = samples.iter().rposition(|&s| s != 0).unwrap();
+        last - ramp
+    }
+    #[test]
+    fn test_sound_timer_duration() {
+        // V0 := 30; ST := V0; loop forever
+        let samples = render_beeper(&[0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04]);
+        assert_eq!(samples.len(), WAV_SAMPLE_RATE as usize);
+        // Half a second of beep from the second instruction of the first
+        // frame until the thirtieth timer tick
+        let half = WAV_SAMPLE_RATE as usize / 2;
+        assert!(samples[..100].iter().any(|&s| s != 0));
+        assert!(beep_end(&samples).abs_diff(half) <= 1);
+    }
+    #[test]
+    fn test_short_beep() {
+        // Wait for the first tick, then ST := 1 at the start of the frame
+        let samples = render_beeper(&[
+            0x60, 0x01, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0xF0, 0x18, 0x12, 0x0C,
+        ]);
+        let tick = WAV_SAMPLE_RATE as usize / 60;
+        let start = samples.iter().position(|&s| s != 0).unwrap();
+        assert!(start > tick);
+        assert!(start < tick * 2);
+        assert!(beep_end(&samples).abs_diff(tick * 2) <= 1);
+    }
+    #[test]
+    fn test_pattern_playback() {
+        let config = BeepConfig {
+            volume: 1.0,
+            ..BeepConfig::default()
+        };
+        // 4000 bits per second at 8000 samples per second, so two samples
+        // per bit
+        let mut tone = ToneGenerator::new(config, 8000);
+        tone.set_pattern(Some(AudioPattern {
+            bits: [0b1100_1010; 16],
+            pitch: AudioPattern::DEFAULT_PITCH,
+        }));
+        tone.set_gate(true);
+        // Let the attack ramp finish, ending on a byte boundary
+        for _ in 0..48 {
+            tone.next_sample();
+        }
+        let mut samples = [0.0f32; 16];
+        tone.fill(&mut samples);
+        let bits: Vec<bool> = samples.iter().step_by(2).map(|&s| s > 0.0).collect();
+        assert_eq!(bits, [true, true, false, false, true, false, true, false]);
+        assert_eq!(samples[0], samples[1]);
+    }
+}
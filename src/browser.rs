@@ -0,0 +1,165 @@
+use crate::asm;
+use crate::rom_config::RomConfigStore;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File extensions the browser lists.
+const ROM_EXTENSIONS: [&str; 4] = ["ch8", "sc8", "xo8", "8o"];
+
+/// A ROM found by the browser.
+pub struct RomEntry {
+    pub path: PathBuf,
+    /// The database title, or the file name when the ROM isn't known.
+    pub title: String,
+    pub platform: Option<&'static str>,
+}
+
+/// The list of ROMs in a directory, with the one under the cursor.
+pub struct RomBrowser {
+    dir: PathBuf,
+    entries: Vec<RomEntry>,
+    selected: usize,
+    /// Why the directory couldn't be listed, if it couldn't.
+    error: Option<String>,
+}
+
+impl RomBrowser {
+    /// Lists the ROMs in `dir` and its subdirectories, looking each one up
+    /// in `store` for its title and platform.
+    pub fn scan(dir: &Path, store: &RomConfigStore) -> Self {
+        let mut paths = Vec::new();
+        let error = find_roms(dir, &mut paths).err();
+        paths.sort();
+        let entries = paths
+            .into_iter()
+            .map(|path| {
+                let config = asm::read_rom(&path)
+                    .ok()
+                    .and_then(|(rom, _)| store.config_for_rom(&rom).ok());
+                let file_name = path
+                    .strip_prefix(dir)
+                    .unwrap_or(&path)
+                    .display()
+                    .to_string();
+                RomEntry {
+                    title: config
+                        .as_ref()
+                        .and_then(|c| c.title.clone())
+                        .unwrap_or(file_name),
+                    platform: config.and_then(|c| c.platform).map(|p| p.name),
+                    path,
+                }
+            })
+            .collect();
+        Self {
+            dir: dir.to_path_buf(),
+            entries,
+            selected: 0,
+            error,
+        }
+    }
+
+    pub fn entries(&self) -> &[RomEntry] {
+        &self.entries
+    }
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.entries.get(self.selected)
+    }
+    /// Moves the cursor, stopping at either end of the list.
+    pub fn move_by(&mut self, delta: isize) {
+        let last = self.entries.len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// The screen as lines of text, fitting `rows` rows of `columns`
+    /// characters.  The line under the cursor is flagged.
+    pub fn lines(&self, rows: usize, columns: usize) -> Vec<(String, bool)> {
+        let mut lines = vec![
+            (
+                fit(&format!("OPEN A ROM FROM {}", self.dir.display()), columns),
+                false,
+            ),
+            (String::new(), false),
+        ];
+        let footer = "UP/DOWN OR 2/8 MOVE  ENTER OR 5 OPEN  ESC BACK";
+        let visible = rows.saturating_sub(lines.len() + 2).max(1);
+        if let Some(error) = &self.error {
+            lines.push((fit(error, columns), false));
+        } else if self.entries.is_empty() {
+            lines.push(("NO ROMS FOUND".to_string(), false));
+        }
+        // Scroll so the cursor stays in the middle of the list when it can
+        let first = self
+            .selected
+            .saturating_sub(visible / 2)
+            .min(self.entries.len().saturating_sub(visible));
+        for (i, entry) in self.entries.iter().enumerate().skip(first).take(visible) {
+            let platform = entry.platform.unwrap_or("");
+            let title_width = columns.saturating_sub(platform.len() + 1);
+            let line = format!(
+                "{:<width$} {}",
+                fit(&entry.title, title_width),
+                platform,
+                width = title_width
+            );
+            lines.push((line, i == self.selected));
+        }
+        while lines.len() < rows.saturating_sub(1) {
+            lines.push((String::new(), false));
+        }
+        lines.push((fit(footer, columns), false));
+        lines
+    }
+}
+
+/// Cuts `text` down to `columns` characters.
+fn fit(text: &str, columns: usize) -> String {
+    text.chars().take(columns).collect()
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Unable to list {}: {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| ROM_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_scrolling() {
+        let mut browser = RomBrowser {
+            dir: PathBuf::from("roms"),
+            entries: (0..30)
+                .map(|n| RomEntry {
+                    path: PathBuf::from(format!("{}.ch8", n)),
+                    title: format!("ROM {}", n),
+                    platform: Some("XO-CHIP"),
+                })
+                .collect(),
+            selected: 0,
+            error: None,
+        };
+        browser.move_by(-1);
+        assert_eq!(browser.selected().unwrap().title, "ROM 0");
+        browser.move_by(20);
+        let lines = browser.lines(10, 20);
+        assert_eq!(lines.len(), 10);
+        let cursor = lines.iter().find(|(_, selected)| *selected).unwrap();
+        assert_eq!(cursor.0, "ROM 20       XO-CHIP");
+        browser.move_by(100);
+        assert_eq!(browser.selected().unwrap().title, "ROM 29");
+    }
+}
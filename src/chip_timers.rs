@@ -10,12 +10,13 @@ impl ChipTimers {
         }
     }
     pub fn tick_second(&mut self) {
-        if (self.delay > 0) | (self.sound > 0) {
-            println!("Delay: {}", self.delay);
-            println!("Sound: {}", self.sound);
-        }
-
         self.delay = self.delay.saturating_sub(1);
         self.sound = self.sound.saturating_sub(1);
     }
 }
+
+impl Default for ChipTimers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,222 @@
+use crate::disasm;
+use crate::machine::ChipMachine;
+use std::fmt;
+
+/// Machine state in a portable trace, one line per instruction, for
+/// checking this emulator against others.
+///
+/// Each line holds the state before an instruction runs, as space-separated
+/// `KEY:VALUE` fields in hex:
+///
+/// ```text
+/// PC:0200 I:0000 V:00000000000000000000000000000000 SP:00 DT:00 ST:00 FB:fd3aa5c5
+/// ```
+///
+/// `V` is V0 to VF as two digits each, `SP` the number of return addresses
+/// on the stack, and `FB` the 32-bit FNV-1a hash of the framebuffer as one
+/// byte per pixel (0 or 1), row by row.  Keys are case-insensitive; fields
+/// a reference leaves out aren't compared and unknown ones are ignored.
+/// Blank lines and lines starting with `#` are skipped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MachineState {
+    pub pc: Option<u16>,
+    pub i: Option<u16>,
+    pub v: Option<[u8; 16]>,
+    pub sp: Option<u8>,
+    pub dt: Option<u8>,
+    pub st: Option<u8>,
+    pub fb: Option<u32>,
+}
+
+impl MachineState {
+    pub fn capture(machine: &ChipMachine) -> Self {
+        let mem = &machine.mem;
+        Self {
+            pc: Some(mem.pc),
+            i: Some(mem.i),
+            v: Some(mem.registers),
+            sp: Some(mem.stack_ptr as u8),
+            dt: Some(mem.timers.delay),
+            st: Some(mem.timers.sound),
+            fb: Some(framebuffer_hash(machine.display.pixels())),
+        }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut state = Self::default();
+        for field in line.split_whitespace() {
+            let (key, value) = field
+                .split_once(':')
+                .ok_or_else(|| format!("Expected KEY:VALUE, not {}", field))?;
+            let number = || {
+                u32::from_str_radix(value, 16)
+                    .map_err(|_| format!("Invalid {} value {}", key, value))
+            };
+            match key.to_ascii_uppercase().as_str() {
+                "PC" => state.pc = Some(number()? as u16),
+                "I" => state.i = Some(number()? as u16),
+                "SP" => state.sp = Some(number()? as u8),
+                "DT" => state.dt = Some(number()? as u8),
+                "ST" => state.st = Some(number()? as u8),
+                "FB" => state.fb = Some(number()?),
+                "V" => {
+                    let mut v = [0u8; 16];
+                    if value.len() != 32 {
+                        return Err(format!("V needs 32 hex digits, not {}", value));
+                    }
+                    for (n, register) in v.iter_mut().enumerate() {
+                        *register = u8::from_str_radix(&value[n * 2..n * 2 + 2], 16)
+                            .map_err(|_| format!("Invalid V value {}", value))?;
+                    }
+                    state.v = Some(v);
+                }
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    /// The fields `reference` has that don't match this state.
+    pub fn mismatches(&self, reference: &MachineState) -> Vec<&'static str> {
+        fn differs<T: PartialEq>(ours: &Option<T>, theirs: &Option<T>) -> bool {
+            theirs.is_some() && ours != theirs
+        }
+        let mut fields = Vec::new();
+        if differs(&self.pc, &reference.pc) {
+            fields.push("PC");
+        }
+        if differs(&self.i, &reference.i) {
+            fields.push("I");
+        }
+        if differs(&self.v, &reference.v) {
+            fields.push("V");
+        }
+        if differs(&self.sp, &reference.sp) {
+            fields.push("SP");
+        }
+        if differs(&self.dt, &reference.dt) {
+            fields.push("DT");
+        }
+        if differs(&self.st, &reference.st) {
+            fields.push("ST");
+        }
+        if differs(&self.fb, &reference.fb) {
+            fields.push("FB");
+        }
+        fields
+    }
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(pc) = self.pc {
+            fields.push(format!("PC:{:04x}", pc));
+        }
+        if let Some(i) = self.i {
+            fields.push(format!("I:{:04x}", i));
+        }
+        if let Some(v) = self.v {
+            let hex: String = v.iter().map(|r| format!("{:02x}", r)).collect();
+            fields.push(format!("V:{}", hex));
+        }
+        if let Some(sp) = self.sp {
+            fields.push(format!("SP:{:02x}", sp));
+        }
+        if let Some(dt) = self.dt {
+            fields.push(format!("DT:{:02x}", dt));
+        }
+        if let Some(st) = self.st {
+            fields.push(format!("ST:{:02x}", st));
+        }
+        if let Some(fb) = self.fb {
+            fields.push(format!("FB:{:08x}", fb));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+pub fn framebuffer_hash(pixels: &[bool]) -> u32 {
+    pixels.iter().fold(0x811c_9dc5, |hash, &pixel| {
+        (hash ^ pixel as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Runs the loaded ROM one instruction per line of `reference`, stopping at
+/// the first state that differs.  Timers tick every `cycles_per_frame`
+/// instructions.  Returns how many lines matched, or a report of both
+/// states at the divergence.
+pub fn compare(machine: &mut ChipMachine, reference: &str) -> Result<usize, String> {
+    let mut matched = 0;
+    let mut cycles = 0;
+    let mut last_instruction = None;
+    for (n, line) in reference.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let theirs = MachineState::parse(line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        // Waits for vblank aren't instructions, so don't appear in traces
+        loop {
+            if cycles == machine.cycles_per_frame {
+                machine.tick_timers();
+                cycles = 0;
+            }
+            if !machine.waiting_for_vblank() {
+                break;
+            }
+            machine.chip_clk()?;
+            cycles += 1;
+        }
+        let ours = MachineState::capture(machine);
+        let fields = ours.mismatches(&theirs);
+        if !fields.is_empty() {
+            let mut report = format!(
+                "Diverged at instruction {} (line {} of the reference): {} differ",
+                matched + 1,
+                n + 1,
+                fields.join(", ")
+            );
+            if let Some(instruction) = last_instruction {
+                report.push_str(&format!("\n  after    {}", instruction));
+            }
+            report.push_str(&format!("\n  expected {}\n  got      {}", theirs, ours));
+            return Err(report);
+        }
+        last_instruction = Some(disasm::current_instruction(machine));
+        machine.chip_clk()?;
+        cycles += 1;
+        matched += 1;
+    }
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::ChipMemory;
+    #[test]
+    fn test_compare() {
+        let new_machine = || {
+            let mut machine = ChipMachine::new(ChipMemory::new());
+            // v0 := 5, v0 += 1, i := 0x300, jump 0x200
+            machine
+                .load_rom_bytes(&[0x60, 0x05, 0x70, 0x01, 0xA3, 0x00, 0x12, 0x00])
+                .unwrap();
+            machine
+        };
+        let mut machine = new_machine();
+        let mut trace = String::from("# recorded\n");
+        for _ in 0..6 {
+            trace.push_str(&format!("{}\n", MachineState::capture(&machine)));
+            machine.chip_clk().unwrap();
+        }
+        assert_eq!(compare(&mut new_machine(), &trace), Ok(6));
+
+        let parsed = MachineState::parse(trace.lines().nth(2).unwrap()).unwrap();
+        assert_eq!(parsed.v.unwrap()[0], 5);
+        let partial = "pc:200\npc:202 v:05000000000000000000000000000000\npc:204 i:0301\n";
+        let report = compare(&mut new_machine(), partial).unwrap_err();
+        assert!(report.starts_with("Diverged at instruction 3 (line 3 of the reference): I"));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::machine::ChipMachine;
+use crate::symbols::Symbols;
+
+/// An instruction in Octo syntax, with addresses shown by label where
+/// there is one.
+pub fn disassemble(op: u16, symbols: &Symbols) -> String {
+    let x = (op >> 8) & 0xF;
+    let y = (op >> 4) & 0xF;
+    let n = op & 0xF;
+    let nn = op & 0xFF;
+    let nnn = op & 0xFFF;
+    let target = || match symbols.name_at(nnn) {
+        Some(name) => name.to_string(),
+        None => format!("{:#05x}", nnn),
+    };
+    match (op >> 12, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => "clear".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "return".to_string(),
+        (0x0, 0x0, 0xC, _) => format!("scroll-down {}", n),
+        (0x0, 0x0, 0xD, _) => format!("scroll-up {}", n),
+        (0x0, 0x0, 0xF, 0xB) => "scroll-right".to_string(),
+        (0x0, 0x0, 0xF, 0xC) => "scroll-left".to_string(),
+        (0x0, 0x0, 0xF, 0xD) => "exit".to_string(),
+        (0x0, 0x0, 0xF, 0xE) => "lores".to_string(),
+        (0x0, 0x0, 0xF, 0xF) => "hires".to_string(),
+        (0x0, ..) => format!("native {}", target()),
+        (0x1, ..) => format!("jump {}", target()),
+        (0x2, ..) => match symbols.name_at(nnn) {
+            Some(name) => name.to_string(),
+            None => format!(":call {:#05x}", nnn),
+        },
+        (0x3, ..) => format!("if v{:x} != {:#04x} then", x, nn),
+        (0x4, ..) => format!("if v{:x} == {:#04x} then", x, nn),
+        (0x5, _, _, 0x0) => format!("if v{:x} != v{:x} then", x, y),
+        (0x5, _, _, 0x2) => format!("save v{:x} - v{:x}", x, y),
+        (0x5, _, _, 0x3) => format!("load v{:x} - v{:x}", x, y),
+        (0x6, ..) => format!("v{:x} := {:#04x}", x, nn),
+        (0x7, ..) => format!("v{:x} += {:#04x}", x, nn),
+        (0x8, ..) => {
+            let operator = match n {
+                0x0 => ":=",
+                0x1 => "|=",
+                0x2 => "&=",
+                0x3 => "^=",
+                0x4 => "+=",
+                0x5 => "-=",
+                0x6 => ">>=",
+                0x7 => "=-",
+                0xE => "<<=",
+                _ => return data(op),
+            };
+            format!("v{:x} {} v{:x}", x, operator, y)
+        }
+        (0x9, _, _, 0x0) => format!("if v{:x} == v{:x} then", x, y),
+        (0xA, ..) => format!("i := {}", target()),
+        (0xB, ..) => format!("jump0 {}", target()),
+        (0xC, ..) => format!("v{:x} := random {:#04x}", x, nn),
+        (0xD, ..) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("if v{:x} -key then", x),
+        (0xE, _, 0xA, 0x1) => format!("if v{:x} key then", x),
+        (0xF, 0x0, 0x0, 0x0) => "i := long".to_string(),
+        (0xF, _, 0x0, 0x1) => format!("plane {}", x),
+        (0xF, 0x0, 0x0, 0x2) => "audio".to_string(),
+        (0xF, ..) => match nn {
+            0x07 => format!("v{:x} := delay", x),
+            0x0A => format!("v{:x} := key", x),
+            0x15 => format!("delay := v{:x}", x),
+            0x18 => format!("buzzer := v{:x}", x),
+            0x1E => format!("i += v{:x}", x),
+            0x29 => format!("i := hex v{:x}", x),
+            0x30 => format!("i := bighex v{:x}", x),
+            0x33 => format!("bcd v{:x}", x),
+            0x3A => format!("pitch := v{:x}", x),
+            0x55 => format!("save v{:x}", x),
+            0x65 => format!("load v{:x}", x),
+            0x75 => format!("saveflags v{:x}", x),
+            0x85 => format!("loadflags v{:x}", x),
+            _ => data(op),
+        },
+        _ => data(op),
+    }
+}
+
+/// A word that isn't an instruction, written as the bytes it holds.
+fn data(op: u16) -> String {
+    format!("{:#04x} {:#04x}", op >> 8, op & 0xFF)
+}
+
+/// The instruction about to run, with where it is: `0x0206 main+6  sprite
+/// v0 v1 5`, and the source line when the ROM was assembled.
+pub fn current_instruction(machine: &ChipMachine) -> String {
+    let pc = machine.mem.pc;
+    let ram = &machine.mem.ram;
+    let op = u16::from_be_bytes([
+        ram[pc as usize % ram.len()],
+        ram[(pc as usize + 1) % ram.len()],
+    ]);
+    let mut text = format!("{:#06x}", pc);
+    if let Some(location) = machine.symbols.locate(pc) {
+        text.push_str(&format!(" {}", location));
+    }
+    text.push_str(&format!("  {}", disassemble(op, &machine.symbols)));
+    if let Some(line) = machine.program.as_ref().and_then(|p| p.line_at(pc)) {
+        text.push_str(&format!("  (line {})", line));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    #[test]
+    fn test_round_trip() {
+        let source = "
+            : main
+              clear
+              v3 := 0x0a
+              if v3 != v4 then v3 += 0xff
+              i := sprite
+              sprite v0 v1 5
+              draw
+              jump main
+            : draw
+              v2 <<= v3
+              i := hex v2
+              return
+            : sprite
+            ";
+        let program = assemble(source).unwrap();
+        let symbols = Symbols::from_program(&program);
+        let listing: Vec<String> = program
+            .bytes
+            .chunks(2)
+            .map(|w| disassemble(u16::from_be_bytes([w[0], w[1]]), &symbols))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "clear",
+                "v3 := 0x0a",
+                "if v3 != v4 then",
+                "v3 += 0xff",
+                "i := sprite",
+                "sprite v0 v1 5",
+                "draw",
+                "jump main",
+                "v2 <<= v3",
+                "i := hex v2",
+                "return",
+            ]
+        );
+        assert_eq!(disassemble(0x2345, &symbols), ":call 0x345");
+        assert_eq!(disassemble(0x8008, &symbols), "0x80 0x08");
+    }
+}
@@ -0,0 +1,62 @@
+use crate::WDW_HEIGHT;
+use crate::WDW_WIDTH;
+
+pub const DISPLAY_SIZE: usize = WDW_WIDTH as usize * WDW_HEIGHT as usize;
+
+/// The emulated monochrome framebuffer, independent of anything that
+/// presents it.
+pub struct Display {
+    display_backing: [bool; DISPLAY_SIZE],
+}
+
+impl Display {
+    pub fn new() -> Self {
+        // initialize to all pixels off at first
+        Self {
+            display_backing: [false; DISPLAY_SIZE],
+        }
+    }
+    pub fn pixels(&self) -> &[bool; DISPLAY_SIZE] {
+        &self.display_backing
+    }
+    pub fn set_display_at_location(
+        &mut self,
+        x: usize,
+        y: usize,
+        value: bool,
+    ) -> Result<(), String> {
+        self.display_backing[WDW_WIDTH as usize * y + x] = value;
+        Ok(())
+    }
+    pub fn print_debug(&self) {
+        println!("{:?}", self.display_backing);
+        for y in 0..WDW_HEIGHT as usize {
+            for x in 0..WDW_WIDTH as usize {
+                print!(
+                    "{}",
+                    if self.display_backing[WDW_WIDTH as usize * y + x] {
+                        1
+                    } else {
+                        0
+                    }
+                );
+            }
+            println!();
+        }
+    }
+    pub fn get_display_at_location(&self, x: usize, y: usize) -> Result<bool, String> {
+        Ok(self.display_backing[(WDW_WIDTH as usize * y + x) % DISPLAY_SIZE])
+    }
+    pub fn replace_display(&mut self, replacement: [bool; DISPLAY_SIZE]) {
+        self.display_backing = replacement;
+    }
+    pub fn clear_display(&mut self) {
+        self.replace_display([false; DISPLAY_SIZE])
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,305 @@
+use crate::display::DISPLAY_SIZE;
+use crate::machine::{ChipMachine, ChipMemory};
+use crate::quirks::Quirks;
+use crate::rng::Generator;
+use crate::watchpoints::Condition;
+
+/// How a number is laid out in RAM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Unsigned binary over this many bytes, most significant first.
+    Binary(u8),
+    /// Decimal over this many bytes, a digit to a byte, as FX33 writes it.
+    Bcd(u8),
+}
+
+/// A number the ROM keeps in RAM, such as its score.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RamValue {
+    pub address: u16,
+    pub encoding: Encoding,
+}
+
+impl RamValue {
+    /// Whether the value fits in an `i64`: up to 7 bytes of binary or 18
+    /// digits.
+    pub fn check(&self) -> Result<(), String> {
+        match self.encoding {
+            Encoding::Binary(len) if len > 7 => {
+                Err(format!("A binary value can be up to 7 bytes, not {}", len))
+            }
+            Encoding::Bcd(len) if len > 18 => {
+                Err(format!("A BCD value can be up to 18 digits, not {}", len))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read(&self, mem: &ChipMemory) -> i64 {
+        let (len, base) = match self.encoding {
+            Encoding::Binary(len) => (len, 256),
+            Encoding::Bcd(len) => (len, 10),
+        };
+        // Wrapping, as BCD bytes that aren't digits can still overflow
+        (0..len as usize).fold(0i64, |value, n| {
+            let byte = mem.ram[(self.address as usize + n) % mem.ram.len()];
+            value.wrapping_mul(base).wrapping_add(byte as i64)
+        })
+    }
+}
+
+/// What an environment runs and how it scores it.
+#[derive(Clone, Debug)]
+pub struct EnvironmentConfig {
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub generator: Generator,
+    /// Frames each step runs for, holding the action's keys down.
+    pub frame_skip: u32,
+    /// The keys each action holds down, key N in bit N.
+    pub actions: Vec<u16>,
+    /// The reward for a step is how much this went up.
+    pub score: Option<RamValue>,
+    /// The episode is over once this holds after a frame, e.g. `[0x3A0]==0`
+    /// for no lives left.
+    pub done: Option<Condition>,
+    /// Ends the episode after this many frames, if it hasn't ended itself.
+    pub max_frames: Option<u64>,
+}
+
+impl Default for EnvironmentConfig {
+    /// The default CHIP-8 settings, four frames a step, and an action for
+    /// doing nothing followed by one for each key.
+    fn default() -> Self {
+        Self {
+            quirks: Quirks::default(),
+            cycles_per_frame: ChipMachine::DEFAULT_CYCLES_PER_FRAME,
+            generator: Generator::default(),
+            frame_skip: 4,
+            actions: std::iter::once(0)
+                .chain((0..16).map(|key| 1 << key))
+                .collect(),
+            score: None,
+            done: None,
+            max_frames: None,
+        }
+    }
+}
+
+/// A ROM wrapped for training agents, after OpenAI Gym: `reset` starts an
+/// episode and `step` plays an action for a few frames, returning the
+/// screen, the reward and whether the episode is over.  Each environment
+/// has its own machine, so any number can run side by side, on as many
+/// threads.
+pub struct Environment {
+    machine: ChipMachine,
+    rom: Vec<u8>,
+    config: EnvironmentConfig,
+    score: i64,
+    frames: u64,
+    done: bool,
+}
+
+impl Environment {
+    pub fn new(rom: &[u8], config: EnvironmentConfig) -> Result<Self, String> {
+        if let Some(score) = &config.score {
+            score.check()?;
+        }
+        if !config.generator.available() {
+            return Err("The vip generator needs the VIP interpreter loaded".to_string());
+        }
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        machine.load_rom_bytes(rom)?;
+        Ok(Self {
+            machine,
+            rom: rom.to_vec(),
+            config,
+            score: 0,
+            frames: 0,
+            done: true,
+        })
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.config.actions.len()
+    }
+    /// Bytes in an observation: one per pixel, row by row.
+    pub fn observation_size(&self) -> usize {
+        DISPLAY_SIZE
+    }
+    pub fn machine(&self) -> &ChipMachine {
+        &self.machine
+    }
+
+    /// Starts a new episode from power-on, with CXNN seeded from `seed`.
+    /// Returns the first observation.
+    pub fn reset(&mut self, seed: u32) -> Result<Vec<u8>, String> {
+        let machine = &mut self.machine;
+        machine.reset();
+        machine.load_rom_bytes(&self.rom)?;
+        machine.quirks = self.config.quirks;
+        machine.cycles_per_frame = self.config.cycles_per_frame;
+        machine.set_generator(self.config.generator);
+        machine.seed_rng(seed);
+        machine.take_sound_update();
+        self.score = self.read_score();
+        self.frames = 0;
+        self.done = false;
+        Ok(self.observation())
+    }
+
+    /// Holds down the keys for `action` for `frame_skip` frames, or until
+    /// the episode ends.  Returns the screen, the change in score, and
+    /// whether the episode is over.
+    pub fn step(&mut self, action: usize) -> Result<(Vec<u8>, f64, bool), String> {
+        if self.done {
+            return Err("The episode is over; reset the environment first".to_string());
+        }
+        let keys = *self
+            .config
+            .actions
+            .get(action)
+            .ok_or_else(|| format!("No action {}", action))?;
+        for (key, down) in self.machine.input.iter_mut().enumerate() {
+            *down = keys >> key & 1 != 0;
+        }
+        for _ in 0..self.config.frame_skip.max(1) {
+            let machine = &mut self.machine;
+            machine.run_cycles(machine.cycles_per_frame)?;
+            machine.tick_timers();
+            machine.take_sound_update();
+            self.frames += 1;
+            self.done = self
+                .config
+                .done
+                .as_ref()
+                .is_some_and(|done| done.holds(&self.machine.mem))
+                || self.config.max_frames.is_some_and(|max| self.frames >= max);
+            if self.done {
+                break;
+            }
+        }
+        let score = self.read_score();
+        let reward = score.wrapping_sub(self.score) as f64;
+        self.score = score;
+        Ok((self.observation(), reward, self.done))
+    }
+
+    /// The screen, a byte of 0 or 1 for each pixel, row by row.
+    pub fn observation(&self) -> Vec<u8> {
+        self.machine
+            .display
+            .pixels()
+            .iter()
+            .map(|&pixel| pixel as u8)
+            .collect()
+    }
+
+    fn read_score(&self) -> i64 {
+        self.config
+            .score
+            .map_or(0, |score| score.read(&self.machine.mem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::symbols::Symbols;
+    #[test]
+    fn test_environment() {
+        // A point for each time round the loop with key 1 held, kept as BCD
+        // at 0x300
+        let program = assemble(
+            "
+            : main
+              v1 := 1
+              loop
+                if v1 key then v0 += 1
+                i := 0x300
+                bcd v0
+                v2 := random 0x3F
+                i := hex v0
+                clear
+                sprite v2 v1 5
+                v3 := 1
+                delay := v3
+                loop
+                  v3 := delay
+                  if v3 != 0 then
+                again
+              again
+            ",
+        )
+        .unwrap();
+        let config = EnvironmentConfig {
+            score: Some(RamValue {
+                address: 0x300,
+                encoding: Encoding::Bcd(3),
+            }),
+            done: Some(Condition::parse("[0x302]==5", &Symbols::default()).unwrap()),
+            ..Default::default()
+        };
+        let mut env = Environment::new(&program.bytes, config).unwrap();
+        assert!(env.step(0).is_err());
+        let first = env.reset(1).unwrap();
+        assert_eq!(first.len(), env.observation_size());
+        assert_eq!(env.action_count(), 17);
+
+        let (_, reward, done) = env.step(0).unwrap();
+        assert_eq!((reward, done), (0.0, false));
+        // Action 2 holds key 1
+        let (screen, reward, done) = env.step(2).unwrap();
+        assert!(reward > 0.0 && !done);
+        let (mut total, mut drawn) = (reward, screen.contains(&1));
+        loop {
+            let (screen, reward, done) = env.step(2).unwrap();
+            total += reward;
+            drawn |= screen.contains(&1);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(total, 5.0);
+        assert!(drawn);
+
+        let too_long = |encoding| EnvironmentConfig {
+            score: Some(RamValue {
+                address: 0x300,
+                encoding,
+            }),
+            ..Default::default()
+        };
+        assert!(Environment::new(&program.bytes, too_long(Encoding::Binary(8))).is_err());
+        assert!(Environment::new(&program.bytes, too_long(Encoding::Bcd(19))).is_err());
+        // Bytes that aren't digits mustn't overflow the read
+        let mut garbage = ChipMemory::new();
+        garbage.ram[0x300..0x312].fill(0xFF);
+        let digits = RamValue {
+            address: 0x300,
+            encoding: Encoding::Bcd(18),
+        };
+        digits.check().unwrap();
+        digits.read(&garbage);
+
+        // Instances run the same on any thread
+        let episodes: Vec<_> = (0..4)
+            .map(|_| {
+                let config = EnvironmentConfig {
+                    done: None,
+                    ..env.config.clone()
+                };
+                let mut env = Environment::new(&program.bytes, config).unwrap();
+                std::thread::spawn(move || {
+                    env.reset(7).unwrap();
+                    (0..20)
+                        .map(|n| env.step(n % 3).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let episodes: Vec<_> = episodes.into_iter().map(|e| e.join().unwrap()).collect();
+        assert!(episodes.iter().all(|e| *e == episodes[0]));
+    }
+}
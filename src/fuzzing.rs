@@ -0,0 +1,68 @@
+use crate::machine::{ChipMachine, ChipMemory};
+use crate::rng;
+
+/// Instructions run per input.  Enough to get through a ROM's setup and
+/// into its main loop, while keeping each run short.
+pub const CYCLES: u32 = 10_000;
+
+/// Runs arbitrary bytes as a ROM, as the fuzz target does: from a fixed
+/// seed, ticking the timers every frame, until the cycles run out or the
+/// machine reports an error.  Returns the instructions run, or the error.
+/// Errors are expected from junk; a panic is a bug.
+pub fn run_rom(rom: &[u8]) -> Result<u32, String> {
+    let mut machine = ChipMachine::new(ChipMemory::new());
+    machine.seed_rng(rng::FIXED_SEED);
+    machine.load_rom_bytes(rom)?;
+    let mut cycles = 0;
+    while cycles < CYCLES {
+        machine.run_cycles(machine.cycles_per_frame)?;
+        machine.tick_timers();
+        machine.take_sound_update();
+        cycles += machine.cycles_per_frame;
+    }
+    Ok(cycles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    /// Each ROM in fuzz/regressions once brought the interpreter down, and
+    /// must now stop with the error given here.
+    const REGRESSIONS: &[(&str, &str)] = &[
+        ("bcd-past-ram.ch8", "Unknown instruction 0000 at 0x0204"),
+        ("call-stack-overflow.ch8", "Call stack overflow at 0x0200"),
+        ("jump0-past-ram.ch8", "Ran off the end of memory at 0x10fe"),
+        ("key-out-of-range.ch8", "Unknown instruction 0000 at 0x0204"),
+        ("load-past-ram.ch8", "Unknown instruction 0000 at 0x0204"),
+        (
+            "return-empty-stack.ch8",
+            "Return with nothing on the stack at 0x0200",
+        ),
+        ("save-past-ram.ch8", "Unknown instruction 0000 at 0x0204"),
+        ("sprite-past-ram.ch8", "Unknown instruction 0000 at 0x0204"),
+        ("unknown-0nnn.ch8", "Unknown instruction 00ff at 0x0200"),
+        ("unknown-8xy8.ch8", "Unknown instruction 8008 at 0x0200"),
+        ("unknown-exnn.ch8", "Unknown instruction e000 at 0x0200"),
+        ("unknown-fxnn.ch8", "Unknown instruction f0ff at 0x0200"),
+    ];
+    #[test]
+    fn test_regressions() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        let expected: Vec<_> = REGRESSIONS.iter().map(|&(name, _)| name).collect();
+        assert_eq!(names, expected, "every regression needs an expected result");
+        for &(name, error) in REGRESSIONS {
+            let rom = fs::read(dir.join(name)).unwrap();
+            assert_eq!(run_rom(&rom), Err(error.to_string()), "{}", name);
+        }
+        // A ROM that keeps going runs out the budget, to the end of a frame
+        let budget = CYCLES..CYCLES + ChipMachine::DEFAULT_CYCLES_PER_FRAME;
+        assert!(run_rom(&[0x12, 0x00]).is_ok_and(|cycles| budget.contains(&cycles)));
+    }
+}
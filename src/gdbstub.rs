@@ -0,0 +1,502 @@
+use crate::machine::ChipMachine;
+use crate::watchpoints::{Access, Register, Watch};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+/// The registers in the order `g` sends them.
+const REGISTERS: [Register; 19] = [
+    Register::V(0x0),
+    Register::V(0x1),
+    Register::V(0x2),
+    Register::V(0x3),
+    Register::V(0x4),
+    Register::V(0x5),
+    Register::V(0x6),
+    Register::V(0x7),
+    Register::V(0x8),
+    Register::V(0x9),
+    Register::V(0xA),
+    Register::V(0xB),
+    Register::V(0xC),
+    Register::V(0xD),
+    Register::V(0xE),
+    Register::V(0xF),
+    Register::I,
+    Register::Pc,
+    Register::Sp,
+];
+
+/// Bytes a register takes up in the `g` packet.
+fn register_size(register: Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+/// Describes `REGISTERS` to the debugger, since it has no built-in CHIP-8
+/// architecture to go by.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.chipn80.chip8\">\n",
+    );
+    for (n, register) in REGISTERS.into_iter().enumerate() {
+        let kind = match register {
+            Register::I => "data_ptr",
+            Register::Pc => "code_ptr",
+            _ => "uint8",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            register.to_string().to_ascii_lowercase(),
+            register_size(register) * 8,
+            kind,
+            n
+        ));
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+enum Packet {
+    Command(String),
+    /// Ctrl-C from the debugger, asking the running program to stop.
+    Interrupt,
+}
+
+/// Lets GDB, or anything else speaking its remote serial protocol, attach
+/// to the machine.  The frontend polls it each frame and only runs the
+/// machine while the debugger has it continuing.
+///
+/// Registers go over the wire as V0 to VF, I, PC and SP, with I and PC two
+/// bytes little-endian.  Breakpoints are `Z0`/`Z1`, and `Z2`, `Z3` and `Z4`
+/// set write, read and access watchpoints.
+pub struct GdbStub {
+    listener: Listener,
+    client: Option<Box<dyn Stream>>,
+    /// Bytes received that don't make a whole packet yet.
+    input: Vec<u8>,
+    running: bool,
+}
+
+impl GdbStub {
+    /// Listens on a TCP port on localhost, or a Unix socket at any other
+    /// path.
+    pub fn listen(address: &str) -> Result<Self, String> {
+        let error = |e: io::Error| format!("Unable to listen for a debugger on {}: {}", address, e);
+        let listener = match address.parse::<u16>() {
+            Ok(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port)).map_err(error)?;
+                listener.set_nonblocking(true).map_err(error)?;
+                Listener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            Err(_) => {
+                let listener = UnixListener::bind(address).map_err(error)?;
+                listener.set_nonblocking(true).map_err(error)?;
+                Listener::Unix(listener)
+            }
+            #[cfg(not(unix))]
+            Err(_) => return Err(format!("Invalid port {}", address)),
+        };
+        println!("Waiting for a debugger on {}", address);
+        Ok(Self {
+            listener,
+            client: None,
+            input: Vec::new(),
+            running: false,
+        })
+    }
+
+    /// Whether the machine should run.  It waits for the debugger to
+    /// attach and continue, and runs freely once it detaches.
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Tells the debugger the machine stopped at a breakpoint or
+    /// watchpoint.
+    pub fn stopped(&mut self) {
+        if self.running {
+            self.running = false;
+            self.send("S05");
+        }
+    }
+
+    /// Accepts a debugger if none is attached, then answers whatever it has
+    /// sent.
+    pub fn poll(&mut self, machine: &mut ChipMachine) -> Result<(), String> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok(client) => {
+                    println!("Debugger attached");
+                    self.client = Some(client);
+                    self.input.clear();
+                    self.running = false;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(format!("Unable to accept a debugger: {}", e)),
+            }
+        }
+        let mut buf = [0u8; 4096];
+        while let Some(client) = &mut self.client {
+            match client.read(&mut buf) {
+                Ok(0) => self.detach(),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => self.detach(),
+            }
+        }
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    self.running = false;
+                    self.send("S02");
+                }
+                Packet::Command(command) => {
+                    if let Some(reply) = handle(&command, machine, &mut self.running) {
+                        self.send(&reply);
+                    }
+                    if command.starts_with('D') || command == "k" {
+                        self.detach();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self) {
+        if self.client.take().is_some() {
+            println!("Debugger detached");
+        }
+        self.input.clear();
+        self.running = true;
+    }
+
+    /// Takes the next packet out of the input, acknowledging it, or asks
+    /// for it again if it arrived damaged.
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match *self.input.first()? {
+                b'$' => {
+                    let end = self.input.iter().position(|&b| b == b'#')?;
+                    let checksum = self.input.get(end + 1..end + 3)?;
+                    let expected = std::str::from_utf8(checksum)
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    let data = &self.input[1..end];
+                    let valid = expected == Some(data.iter().fold(0u8, |a, &b| a.wrapping_add(b)));
+                    let command = String::from_utf8_lossy(data).into_owned();
+                    self.input.drain(..end + 3);
+                    if valid {
+                        self.write(b"+");
+                        return Some(Packet::Command(command));
+                    }
+                    self.write(b"-");
+                }
+                0x03 => {
+                    self.input.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                // Acknowledgements of our replies, which are sent reliably
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        self.write(format!("${}#{:02x}", data, checksum).as_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        let mut written = 0;
+        while written < bytes.len() {
+            match client.write(&bytes[written..]) {
+                Ok(n) if n > 0 => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                _ => return self.detach(),
+            }
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|n| {
+            text.get(n..n + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Answers a packet, or returns `None` when the answer comes later, as for
+/// `c` once the machine stops.  Anything not understood gets the empty
+/// reply the protocol uses for that.
+fn handle(packet: &str, machine: &mut ChipMachine, running: &mut bool) -> Option<String> {
+    const ERROR: &str = "E01";
+    let Some(command) = packet.chars().next() else {
+        return Some(String::new());
+    };
+    let args = &packet[command.len_utf8()..];
+    let ram_len = machine.mem.ram.len();
+    // ADDR,LEN as the memory and breakpoint packets give them
+    let range = |text: &str| -> Option<(usize, usize)> {
+        let (address, len) = text.split_once(',')?;
+        let (address, len) = (parse_hex(address)? as usize, parse_hex(len)? as usize);
+        (address + len <= ram_len).then_some((address, len))
+    };
+    let reply = match command {
+        '?' => "S05".to_string(),
+        'g' => {
+            let mut bytes = Vec::new();
+            for register in REGISTERS {
+                let value = register.value(&machine.mem).to_le_bytes();
+                bytes.extend_from_slice(&value[..register_size(register)]);
+            }
+            encode_hex(&bytes)
+        }
+        'G' => match decode_hex(args) {
+            Some(bytes) => {
+                let mut bytes = bytes.into_iter();
+                for register in REGISTERS {
+                    let mut value = [0u8; 2];
+                    for byte in value.iter_mut().take(register_size(register)) {
+                        *byte = bytes.next().unwrap_or(0);
+                    }
+                    register.set(&mut machine.mem, u16::from_le_bytes(value));
+                }
+                "OK".to_string()
+            }
+            None => ERROR.to_string(),
+        },
+        'p' => match parse_hex(args).and_then(|n| REGISTERS.get(n as usize)) {
+            Some(&register) => {
+                let value = register.value(&machine.mem).to_le_bytes();
+                encode_hex(&value[..register_size(register)])
+            }
+            None => ERROR.to_string(),
+        },
+        'P' => {
+            let register = args
+                .split_once('=')
+                .and_then(|(n, value)| Some((REGISTERS.get(parse_hex(n)? as usize)?, value)))
+                .and_then(|(register, value)| Some((*register, decode_hex(value)?)));
+            match register {
+                Some((register, bytes)) => {
+                    let mut value = [0u8; 2];
+                    for (byte, new) in value.iter_mut().zip(bytes) {
+                        *byte = new;
+                    }
+                    register.set(&mut machine.mem, u16::from_le_bytes(value));
+                    "OK".to_string()
+                }
+                None => ERROR.to_string(),
+            }
+        }
+        'm' => match range(args) {
+            Some((address, len)) => encode_hex(&machine.mem.ram[address..address + len]),
+            None => ERROR.to_string(),
+        },
+        'M' => {
+            let write = args
+                .split_once(':')
+                .and_then(|(at, data)| Some((range(at)?, decode_hex(data)?)));
+            match write {
+                Some(((address, len), data)) if data.len() == len => {
+                    machine.mem.ram[address..address + len].copy_from_slice(&data);
+                    "OK".to_string()
+                }
+                _ => ERROR.to_string(),
+            }
+        }
+        'Z' | 'z' => {
+            let insert = command == 'Z';
+            let Some((kind, at)) = args.split_once(',') else {
+                return Some(ERROR.to_string());
+            };
+            match (kind, range(at)) {
+                (_, None) => ERROR.to_string(),
+                ("0" | "1", Some((address, _))) => {
+                    if insert {
+                        machine.breakpoints.insert(address as u16);
+                    } else {
+                        machine.breakpoints.remove(&(address as u16));
+                    }
+                    "OK".to_string()
+                }
+                ("2" | "3" | "4", Some((address, len))) => {
+                    let range = address as u16..=(address + len.max(1) - 1) as u16;
+                    let accesses: &[Access] = match kind {
+                        "2" => &[Access::Write],
+                        "3" => &[Access::Read],
+                        _ => &[Access::Read, Access::Write],
+                    };
+                    for &access in accesses {
+                        let watch = Watch::Memory {
+                            range: range.clone(),
+                            access,
+                        };
+                        if insert {
+                            machine.watches.push(watch);
+                        } else if let Some(n) = machine.watches.iter().position(|w| {
+                            matches!(w, Watch::Memory { range: r, access: a } if *r == range && *a == access)
+                        }) {
+                            machine.watches.remove(n);
+                        }
+                    }
+                    "OK".to_string()
+                }
+                _ => String::new(),
+            }
+        }
+        'c' | 's' => {
+            if let Some(address) = parse_hex(args) {
+                machine.mem.pc = address as u16 & 0xFFF;
+            }
+            if command == 'c' {
+                *running = true;
+                return None;
+            }
+            match machine.chip_clk() {
+                Ok(()) => "S05".to_string(),
+                Err(e) => {
+                    // Kept off stdout, which the terminal frontend draws on
+                    eprintln!("{}", e);
+                    // SIGILL
+                    "S04".to_string()
+                }
+            }
+        }
+        'D' => {
+            *running = true;
+            "OK".to_string()
+        }
+        'k' => return None,
+        'H' | 'T' => "OK".to_string(),
+        'q' => {
+            if args.starts_with("Supported") {
+                "PacketSize=4000;qXfer:features:read+".to_string()
+            } else if let Some(at) = args.strip_prefix("Xfer:features:read:target.xml:") {
+                let xml = target_xml();
+                let window = at.split_once(',').and_then(|(offset, len)| {
+                    let offset = (parse_hex(offset)? as usize).min(xml.len());
+                    Some((offset, (offset + parse_hex(len)? as usize).min(xml.len())))
+                });
+                match window {
+                    Some((offset, end)) => {
+                        let more = if end < xml.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &xml[offset..end])
+                    }
+                    None => ERROR.to_string(),
+                }
+            } else {
+                match args {
+                    "Attached" => "1".to_string(),
+                    "C" => "QC1".to_string(),
+                    "fThreadInfo" => "m1".to_string(),
+                    "sThreadInfo" => "l".to_string(),
+                    "Offsets" => "Text=0;Data=0;Bss=0".to_string(),
+                    "Symbol::" => "OK".to_string(),
+                    _ => String::new(),
+                }
+            }
+        }
+        _ => String::new(),
+    };
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::ChipMemory;
+    #[test]
+    fn test_packets() {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        // v0 := 5, i := 0x300, save v0
+        machine
+            .load_rom_bytes(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55])
+            .unwrap();
+        let mut running = false;
+        let mut send = |packet: &str| handle(packet, &mut machine, &mut running);
+        assert_eq!(send("s").as_deref(), Some("S05"));
+        let registers = send("g").unwrap();
+        assert_eq!(&registers[..4], "0500");
+        // I, then the PC little-endian
+        assert_eq!(&registers[32..40], "00000202");
+        assert_eq!(send("p11").as_deref(), Some("0202"));
+        assert_eq!(send("P10=a002").as_deref(), Some("OK"));
+        assert_eq!(send("m200,2").as_deref(), Some("6005"));
+        assert_eq!(send("M300,2:abcd").as_deref(), Some("OK"));
+        assert_eq!(send("m300,2").as_deref(), Some("abcd"));
+        assert_eq!(send("mfff,2").as_deref(), Some("E01"));
+        assert_eq!(send("Z2,300,1").as_deref(), Some("OK"));
+        // Malformed packets still get an answer
+        assert_eq!(send("Z0").as_deref(), Some("E01"));
+        assert_eq!(send("z1300").as_deref(), Some("E01"));
+        assert_eq!(
+            send("qXfer:features:read:target.xml:0").as_deref(),
+            Some("E01")
+        );
+        assert_eq!(send("c"), None);
+        assert!(send("qXfer:features:read:target.xml:0,1000")
+            .unwrap()
+            .contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>"));
+        assert_eq!(send("vMustReplyEmpty").as_deref(), Some(""));
+        // A byte that isn't ASCII comes through as U+FFFD
+        let garbled = String::from_utf8_lossy(&[0xFF, b'1']);
+        assert_eq!(send(&garbled).as_deref(), Some(""));
+        assert!(running);
+        assert_eq!(machine.mem.i, 0x2A0);
+        assert_eq!(machine.watches.len(), 1);
+    }
+}
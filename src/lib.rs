@@ -0,0 +1,54 @@
+pub mod asm;
+pub mod audio;
+#[cfg(feature = "sdl")]
+pub mod browser;
+pub mod chip_timers;
+pub mod difftest;
+pub mod disasm;
+pub mod display;
+pub mod environment;
+pub mod fuzzing;
+pub mod gdbstub;
+pub mod instruction;
+pub mod json;
+pub mod machine;
+pub mod memview;
+pub mod movie;
+pub mod options;
+pub mod phosphor;
+pub mod quirks;
+pub mod recorder;
+#[cfg(feature = "sdl")]
+pub mod renderer;
+pub mod rng;
+pub mod rom_config;
+pub mod screenshot;
+pub mod script;
+#[cfg(feature = "sdl")]
+pub mod sdl_frontend;
+pub mod sha1;
+pub mod sprites;
+pub mod symbols;
+#[cfg(feature = "sdl")]
+pub mod text;
+pub mod trace;
+#[cfg(unix)]
+pub mod tui;
+pub mod watch;
+pub mod watchpoints;
+pub mod wav;
+
+use std::time::SystemTime;
+
+pub const WDW_SIZE_SCALAR: u32 = 8;
+pub const WDW_WIDTH: u32 = 64;
+pub const WDW_HEIGHT: u32 = 32;
+/// Recordings are scaled down from the window size to keep them small.
+pub const RECORDING_SCALAR: u32 = 4;
+
+pub(crate) fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|t| t.as_millis())
+        .unwrap_or(0)
+}
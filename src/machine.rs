@@ -0,0 +1,891 @@
+use crate::asm::{self, Program};
+use crate::audio::{AudioPattern, SoundEvent, SoundUpdate};
+use crate::chip_timers;
+use crate::difftest::MachineState;
+use crate::display::Display;
+use crate::instruction;
+use crate::quirks::Quirks;
+use crate::rng::{self, Generator};
+use crate::sha1::sha1_hex;
+use crate::symbols::Symbols;
+use crate::trace::{self, Snapshot, TraceFormat, Tracer};
+use crate::watchpoints::{self, Access, MemoryAccess, Watch};
+use crate::WDW_HEIGHT;
+use crate::WDW_WIDTH;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// The emulated machine on its own: memory, CPU, framebuffer and keypad.
+/// Frontends own one of these and decide how it is shown and fed input.
+pub struct ChipMachine {
+    pub mem: ChipMemory,
+    pub display: Display,
+    pub input: [bool; 16],
+    pub(crate) rng: rng::RandomNumberGenerator,
+    /// Instructions executed per 60 Hz frame.
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    /// SHA-1 of the loaded ROM, as hex.
+    rom_sha1: String,
+    /// Set by a draw under the vblank quirk; the CPU idles until the next
+    /// timer tick.
+    waiting_for_vblank: bool,
+    /// Emulated time is kept as timer ticks plus instructions into the
+    /// current tick.
+    ticks: u64,
+    cycles_since_tick: u32,
+    sound_events: Vec<SoundEvent>,
+    /// Addresses `run_cycles` stops at before executing.
+    pub breakpoints: BTreeSet<u16>,
+    /// The breakpoint last stopped at, which is let through on resuming.
+    stopped_at: Option<u16>,
+    /// Watchpoints and conditional breakpoints, checked around each
+    /// instruction.
+    pub watches: Vec<Watch>,
+    /// Why `run_cycles` last stopped early, until the next instruction runs.
+    stop_reason: Option<String>,
+    /// The loaded program's labels and source lines, when it was assembled
+    /// from source.
+    pub program: Option<Program>,
+    /// Labels for the debugger.
+    pub symbols: Symbols,
+    /// Where to read `symbols` from, instead of looking next to the ROM.
+    pub symbol_file: Option<PathBuf>,
+    /// Logs each instruction as it runs, when tracing.
+    pub tracer: Option<Tracer>,
+}
+
+impl ChipMachine {
+    /// Matches the 720 Hz clock of the windowed loop.
+    pub const DEFAULT_CYCLES_PER_FRAME: u32 = 12;
+    pub const TIMER_HZ: f64 = 60.0;
+
+    pub fn new(mem: ChipMemory) -> Self {
+        let mut rng = rng::RandomNumberGenerator::new(Generator::default(), rng::FIXED_SEED);
+        rng.seed_with_time();
+        Self {
+            mem,
+            display: Display::new(),
+            input: [false; 16],
+            rng,
+            cycles_per_frame: Self::DEFAULT_CYCLES_PER_FRAME,
+            quirks: Quirks::default(),
+            rom_sha1: String::new(),
+            waiting_for_vblank: false,
+            ticks: 0,
+            cycles_since_tick: 0,
+            sound_events: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            stopped_at: None,
+            watches: Vec::new(),
+            stop_reason: None,
+            program: None,
+            symbols: Symbols::default(),
+            symbol_file: None,
+            tracer: None,
+        }
+    }
+
+    /// Runs the 60 Hz side of the machine.
+    pub fn tick_timers(&mut self) {
+        let was_beeping = self.mem.timers.sound > 0;
+        self.mem.timers.tick_second();
+        self.ticks += 1;
+        self.cycles_since_tick = 0;
+        self.waiting_for_vblank = false;
+        if was_beeping & (self.mem.timers.sound == 0) {
+            self.push_sound_event(false);
+        }
+    }
+
+    /// Puts the machine back the way it was at power-on, ready for a ROM.
+    /// Emulated time keeps running so the beeper stays in step.
+    pub fn reset(&mut self) {
+        if self.mem.timers.sound > 0 {
+            self.push_sound_event(false);
+        }
+        self.mem = ChipMemory::new();
+        self.display.clear_display();
+        self.input = [false; 16];
+        self.waiting_for_vblank = false;
+        self.stopped_at = None;
+        self.stop_reason = None;
+        for watch in &mut self.watches {
+            if let Watch::When { held, .. } = watch {
+                *held = false;
+            }
+        }
+    }
+
+    /// Restarts the random number generator behind CXNN from `seed`.
+    pub fn seed_rng(&mut self, seed: u32) {
+        self.rng = rng::RandomNumberGenerator::new(self.rng.generator, seed);
+    }
+    /// Switches CXNN to `generator`, starting it from the current seed.
+    pub fn set_generator(&mut self, generator: Generator) {
+        self.rng = rng::RandomNumberGenerator::new(generator, self.rng.seed);
+    }
+    pub fn rng_seed(&self) -> u32 {
+        self.rng.seed
+    }
+    pub fn generator(&self) -> Generator {
+        self.rng.generator
+    }
+
+    /// Instructions run since the timers last ticked.
+    pub fn cycles_this_frame(&self) -> u32 {
+        self.cycles_since_tick
+    }
+
+    /// Seconds of emulated time since the machine started.
+    pub fn emulated_time(&self) -> f64 {
+        let cycles = self.cycles_since_tick.min(self.cycles_per_frame.max(1));
+        (self.ticks as f64 + cycles as f64 / self.cycles_per_frame.max(1) as f64) / Self::TIMER_HZ
+    }
+
+    /// Hands over the beeper's changes since the last call, for the audio
+    /// backend to play back at the right moments.
+    pub fn take_sound_update(&mut self) -> SoundUpdate {
+        SoundUpdate {
+            events: std::mem::take(&mut self.sound_events),
+            pattern: self.audio_pattern(),
+            time: self.emulated_time(),
+        }
+    }
+
+    fn push_sound_event(&mut self, on: bool) {
+        let event = SoundEvent {
+            time: self.emulated_time(),
+            on,
+        };
+        self.sound_events.push(event);
+    }
+
+    /// The XO-CHIP audio pattern the beeper should play, if the ROM has
+    /// loaded one.
+    pub fn audio_pattern(&self) -> Option<AudioPattern> {
+        self.mem.audio_pattern.map(|bits| AudioPattern {
+            bits,
+            pitch: self.mem.pitch,
+        })
+    }
+
+    /// Runs `cycles` instructions, the CPU's share of one 60 Hz frame.
+    /// Returns false if it stopped early at a breakpoint or watchpoint.
+    pub fn run_cycles(&mut self, cycles: u32) -> Result<bool, String> {
+        for _ in 0..cycles {
+            if self.at_breakpoint() {
+                return Ok(false);
+            }
+            if self.watches.is_empty() {
+                self.chip_clk()?;
+            } else if let Some(reason) = self.chip_clk_watched()? {
+                self.stop_reason = Some(reason);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether to stop before the next instruction.  Running again from a
+    /// breakpoint carries on past it.
+    fn at_breakpoint(&mut self) -> bool {
+        let pc = self.mem.pc;
+        let resuming = self.stopped_at.take() == Some(pc);
+        let reason = self.breakpoint_reason(pc);
+        if resuming {
+            return false;
+        }
+        match reason {
+            Some(reason) => {
+                self.stopped_at = Some(pc);
+                self.stop_reason = Some(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Why to stop before the instruction at `pc`, if there is a reason.
+    /// Conditions that stop when they become true see every instruction.
+    fn breakpoint_reason(&mut self, pc: u16) -> Option<String> {
+        let mut reason = self
+            .breakpoints
+            .contains(&pc)
+            .then(|| "Breakpoint".to_string());
+        for watch in &mut self.watches {
+            match watch {
+                Watch::At(address, condition)
+                    if *address == pc && reason.is_none() && condition.holds(&self.mem) =>
+                {
+                    reason = Some(format!("Breakpoint, {}", condition));
+                }
+                Watch::When { condition, held } => {
+                    let holds = condition.holds(&self.mem);
+                    if holds && !*held && reason.is_none() {
+                        reason = Some(format!("Condition met, {}", condition));
+                    }
+                    *held = holds;
+                }
+                _ => {}
+            }
+        }
+        reason
+    }
+
+    /// Runs an instruction, watching what it reads, writes and changes.
+    /// Returns why to stop after it, if a watchpoint was hit.
+    fn chip_clk_watched(&mut self) -> Result<Option<String>, String> {
+        let before: Vec<u16> = self
+            .watches
+            .iter()
+            .map(|watch| match watch {
+                Watch::Change(register) => register.value(&self.mem),
+                _ => 0,
+            })
+            .collect();
+        self.mem.accesses = Some(Vec::new());
+        let result = self.chip_clk();
+        let accesses = self.mem.accesses.take().unwrap_or_default();
+        result?;
+        for (watch, old) in self.watches.iter().zip(before) {
+            match watch {
+                Watch::Memory { range, access } => {
+                    let hit = accesses
+                        .iter()
+                        .find(|a| a.access == *access && range.contains(&a.address));
+                    if let Some(hit) = hit {
+                        return Ok(Some(match hit.access {
+                            Access::Read => {
+                                format!("Read {:#04x} from {:#06x}", hit.value, hit.address)
+                            }
+                            Access::Write => {
+                                format!("Wrote {:#04x} to {:#06x}", hit.value, hit.address)
+                            }
+                        }));
+                    }
+                }
+                Watch::Change(register) => {
+                    let new = register.value(&self.mem);
+                    if old != new {
+                        return Ok(Some(format!(
+                            "{} changed from {:#x} to {:#x}",
+                            register, old, new
+                        )));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Why `run_cycles` stopped, while the machine is still stopped there.
+    pub fn stop_reason(&self) -> Option<&str> {
+        self.stop_reason.as_deref()
+    }
+
+    /// Sets a breakpoint at `address`, or clears the one already there.
+    /// Returns whether one is now set.
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.breakpoints.remove(&address) {
+            false
+        } else {
+            self.breakpoints.insert(address)
+        }
+    }
+
+    pub fn load_rom(&mut self, rom_path: &str) -> Result<(), String> {
+        println!("Reading rom {}", rom_path);
+        let (cts, program) = asm::read_rom(Path::new(rom_path))?;
+        self.load_rom_bytes(&cts)?;
+        if let Some(program) = &program {
+            println!("Assembled {} bytes", cts.len());
+            self.breakpoints
+                .extend(program.breakpoints.iter().map(|&(_, address)| address));
+        }
+        self.symbols = self.load_symbols(Path::new(rom_path), program.as_ref())?;
+        self.program = program;
+        Ok(())
+    }
+    /// Labels from the symbol file if one was given, the assembler, or a
+    /// `.sym` file beside the ROM, in that order.
+    fn load_symbols(&self, rom_path: &Path, program: Option<&Program>) -> Result<Symbols, String> {
+        if let Some(path) = &self.symbol_file {
+            return Symbols::load(path);
+        }
+        if let Some(program) = program {
+            return Ok(Symbols::from_program(program));
+        }
+        let beside = rom_path.with_extension("sym");
+        if beside.is_file() {
+            println!("Reading symbols from {}", beside.display());
+            return Symbols::load(&beside);
+        }
+        Ok(Symbols::default())
+    }
+
+    /// Sets a breakpoint at a label or hex address, or a watchpoint in one
+    /// of the forms `Watch::parse` reads.
+    pub fn break_at(&mut self, spec: &str) -> Result<(), String> {
+        match Watch::parse(spec, &self.symbols)? {
+            Some(watch) => self.watches.push(watch),
+            None => {
+                self.breakpoints
+                    .insert(watchpoints::locate(spec.trim(), &self.symbols)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears every breakpoint and watchpoint.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.watches.clear();
+    }
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), String> {
+        let start = ChipMemory::ROM_STARTING_MEMORY_LOCATION;
+        if rom.len() > self.mem.ram.len() - start {
+            return Err(format!("Rom is too large ({} bytes)", rom.len()));
+        }
+        self.mem.ram[start..start + rom.len()].copy_from_slice(rom);
+        self.mem.pc = start as u16;
+        self.rom_sha1 = sha1_hex(rom);
+        Ok(())
+    }
+    pub fn rom_sha1(&self) -> &str {
+        &self.rom_sha1
+    }
+    pub fn chip_clk(&mut self) -> Result<(), String> {
+        self.stop_reason = None;
+        let result = if self.waiting_for_vblank {
+            Ok(())
+        } else if self.tracer.is_some() {
+            self.execute_traced()
+        } else {
+            self.execute()
+        };
+        self.cycles_since_tick += 1;
+        result
+    }
+    fn execute_traced(&mut self) -> Result<(), String> {
+        let pc = self.mem.pc;
+        let format = match &self.tracer {
+            Some(tracer) if tracer.wants(pc) => tracer.format,
+            _ => return self.execute(),
+        };
+        let byte = |address: u16| self.mem.ram.get(address as usize).copied().unwrap_or(0);
+        let op = u16::from_be_bytes([byte(pc), byte(pc.wrapping_add(1))]);
+        let before = Snapshot::take(&self.mem);
+        let state = (format == TraceFormat::State).then(|| MachineState::capture(self));
+        self.execute()?;
+        let line = match state {
+            Some(state) => state.to_string(),
+            None => trace::text_line(pc, op, &before, &self.mem, &self.symbols),
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write(line)?;
+        }
+        Ok(())
+    }
+    /// Whether a draw under the vblank quirk is holding up the CPU.
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+    fn execute(&mut self) -> Result<(), String> {
+        let instruction = self.mem.get_instruction()?;
+        let (op, at) = (instruction.val, self.mem.pc.wrapping_sub(2));
+        let unknown = move || {
+            format!(
+                "Unknown instruction {:02x}{:02x} at {:#06x}",
+                op[0], op[1], at
+            )
+        };
+
+        let first_nibble = instruction.get_first_nibble();
+        let second_nibble = instruction.get_second_nibble();
+        let third_nibble = instruction.get_third_nibble();
+
+        let nn = instruction.get_nn();
+        let nnn = instruction.get_nnn();
+
+        match first_nibble {
+            0x0 => {
+                if instruction.val[1] == 0xE0 {
+                    self.display.clear_display()
+                } else if instruction.val[1] == 0xEE {
+                    // Return from subroutine
+                    if self.mem.stack_ptr == 0 {
+                        return Err(format!(
+                            "Return with nothing on the stack at {:#06x}",
+                            self.mem.pc.wrapping_sub(2)
+                        ));
+                    }
+                    self.mem.stack_ptr -= 1;
+                    self.mem.pc = self.mem.stack[self.mem.stack_ptr];
+                } else {
+                    // Machine code calls can't be run
+                    return Err(unknown());
+                }
+            }
+            0xA0 => self.mem.i = nnn,
+            0x60 => self.mem.registers[second_nibble as usize] = nn,
+            0xD0 => {
+                let x_draw_coord = self.mem.registers[second_nibble as usize] % WDW_WIDTH as u8;
+                let y_draw_coord = self.mem.registers[third_nibble as usize] % WDW_HEIGHT as u8;
+                let sprite_height = instruction.val[1] & 0x0F;
+                self.mem.registers[0xF] = 0x0;
+                for yi in 0..sprite_height {
+                    let sprite_data = self.mem.read(yi as usize);
+                    // println!("{:#010b}", sprite_data);
+                    let mut y = (yi + y_draw_coord) as usize;
+                    if y >= WDW_HEIGHT as usize {
+                        if !self.quirks.wrap {
+                            break;
+                        }
+                        y %= WDW_HEIGHT as usize;
+                    }
+                    for xi in 0..8u8 {
+                        let mut x = (xi + x_draw_coord) as usize;
+                        if x >= WDW_WIDTH as usize {
+                            if !self.quirks.wrap {
+                                break;
+                            }
+                            x %= WDW_WIDTH as usize;
+                        }
+                        let pixel_data =
+                            ((sprite_data << (xi) as u32) & 0b1000_0000) == 0b1000_0000;
+                        let cur_val = self.display.get_display_at_location(x, y)?;
+
+                        // let ret_val: bool;
+                        // if pixel_data & cur_val {
+                        //     ret_val = false;
+                        //     self.mem.registers[0xF] = 0x1;
+                        // } else if pixel_data {
+                        //     ret_val = true;
+                        // } else {
+                        //     ret_val = false;
+                        // }
+
+                        // Ohhhh.. you don't make any changes if pixel_data is false....
+                        // Me big dum
+                        let ret_val: bool;
+                        if !pixel_data {
+                            continue;
+                        } else if cur_val {
+                            ret_val = false;
+                            self.mem.registers[0xF] = 0x1;
+                        } else {
+                            ret_val = true;
+                        }
+
+                        self.display.set_display_at_location(x, y, ret_val)?;
+                    }
+                }
+                self.waiting_for_vblank = self.quirks.vblank;
+            }
+            0x70 => {
+                (self.mem.registers[second_nibble as usize], _) =
+                    self.mem.registers[second_nibble as usize].overflowing_add(nn)
+            }
+            0x10 => self.mem.pc = nnn,
+            0x20 => {
+                if self.mem.stack_ptr == self.mem.stack.len() {
+                    return Err(format!(
+                        "Call stack overflow at {:#06x}",
+                        self.mem.pc.wrapping_sub(2)
+                    ));
+                }
+                self.mem.stack[self.mem.stack_ptr] = self.mem.pc;
+                self.mem.stack_ptr += 1;
+                self.mem.pc = nnn
+            }
+            0x30 => {
+                let x = self.mem.registers[second_nibble as usize];
+                if x == nn {
+                    self.mem.pc += 2
+                }
+            }
+            0x40 => {
+                let x = self.mem.registers[second_nibble as usize];
+                if x != nn {
+                    self.mem.pc += 2;
+                }
+            }
+            0x50 => {
+                let x = self.mem.registers[second_nibble as usize];
+                let y = self.mem.registers[third_nibble as usize];
+                if x == y {
+                    self.mem.pc += 2;
+                }
+            }
+            0x90 => {
+                let x = self.mem.registers[second_nibble as usize];
+                let y = self.mem.registers[third_nibble as usize];
+                if x != y {
+                    self.mem.pc += 2;
+                }
+            }
+            0xB0 => {
+                let reg = if self.quirks.jump { second_nibble } else { 0 };
+                let offset = self.mem.registers[reg as usize];
+                self.mem.pc = nnn + offset as u16
+            }
+            0xC0 => {
+                self.mem.registers[second_nibble as usize] = nn & self.rng.next();
+            }
+            0x80 => match instruction.val[1] & 0x0F {
+                0x0 => {
+                    self.mem.registers[second_nibble as usize] =
+                        self.mem.registers[third_nibble as usize]
+                }
+                0x1 => {
+                    self.mem.registers[second_nibble as usize] |=
+                        self.mem.registers[third_nibble as usize];
+                    if self.quirks.logic {
+                        self.mem.registers[0xF] = 0;
+                    }
+                }
+                0x2 => {
+                    self.mem.registers[second_nibble as usize] &=
+                        self.mem.registers[third_nibble as usize];
+                    if self.quirks.logic {
+                        self.mem.registers[0xF] = 0;
+                    }
+                }
+                0x3 => {
+                    self.mem.registers[second_nibble as usize] ^=
+                        self.mem.registers[third_nibble as usize];
+                    if self.quirks.logic {
+                        self.mem.registers[0xF] = 0;
+                    }
+                }
+                0x4 => {
+                    let x = self.mem.registers[second_nibble as usize];
+                    let y = self.mem.registers[third_nibble as usize];
+                    let (ret_val, overflow) = x.overflowing_add(y);
+                    self.mem.registers[second_nibble as usize] = ret_val;
+                    self.mem.registers[0xF] = if overflow { 0x1 } else { 0x0 };
+                }
+                0x5 => {
+                    let x = self.mem.registers[second_nibble as usize];
+                    let y = self.mem.registers[third_nibble as usize];
+
+                    let (ret_val, _) = self.mem.registers[second_nibble as usize]
+                        .overflowing_sub(self.mem.registers[third_nibble as usize]);
+                    self.mem.registers[second_nibble as usize] = ret_val;
+                    self.mem.registers[0xF] = if x >= y { 0x1 } else { 0x0 };
+                }
+                0x7 => {
+                    let x = self.mem.registers[second_nibble as usize];
+                    let y = self.mem.registers[third_nibble as usize];
+                    let (ret_val, _) = y.overflowing_sub(x);
+                    self.mem.registers[second_nibble as usize] = ret_val;
+                    self.mem.registers[0xF] = if y >= x { 0x1 } else { 0x0 };
+                }
+                0x6 => {
+                    if !self.quirks.shift {
+                        self.mem.registers[second_nibble as usize] =
+                            self.mem.registers[third_nibble as usize];
+                    }
+                    let orig = self.mem.registers[second_nibble as usize];
+                    self.mem.registers[second_nibble as usize] >>= 1;
+                    self.mem.registers[0xF] = if (orig & 0b0000_0001) == 1 { 0x1 } else { 0x0 };
+                }
+                0xE => {
+                    if !self.quirks.shift {
+                        self.mem.registers[second_nibble as usize] =
+                            self.mem.registers[third_nibble as usize];
+                    }
+                    let orig = self.mem.registers[second_nibble as usize];
+                    self.mem.registers[second_nibble as usize] <<= 1;
+                    self.mem.registers[0xF] = if (orig & 0b1000_0000) == 0 { 0x0 } else { 0x1 };
+                }
+                _ => return Err(unknown()),
+            },
+            0xE0 => match instruction.val[1] {
+                0x9E => {
+                    // skip if key is pressed; only the low nibble picks the key
+                    let which_key = self.mem.registers[second_nibble as usize] & 0xF;
+                    if self.input[which_key as usize] {
+                        self.mem.pc += 2;
+                    }
+                }
+                0xA1 => {
+                    // skip if key is not pressed
+                    let which_key = self.mem.registers[second_nibble as usize] & 0xF;
+                    if !self.input[which_key as usize] {
+                        self.mem.pc += 2;
+                    }
+                }
+                _ => return Err(unknown()),
+            },
+            0xF0 => match instruction.val[1] {
+                0x07 => self.mem.registers[second_nibble as usize] = self.mem.timers.delay,
+                0x15 => self.mem.timers.delay = self.mem.registers[second_nibble as usize],
+                0x18 => {
+                    let was_beeping = self.mem.timers.sound > 0;
+                    self.mem.timers.sound = self.mem.registers[second_nibble as usize];
+                    let beeping = self.mem.timers.sound > 0;
+                    if beeping != was_beeping {
+                        self.push_sound_event(beeping);
+                    }
+                }
+                0x1E => {
+                    let (o, _) = self
+                        .mem
+                        .i
+                        .overflowing_add(self.mem.registers[second_nibble as usize] as u16);
+                    self.mem.i = o;
+                }
+                0x55 => {
+                    for i in 0..=second_nibble as usize {
+                        self.mem.write(i, self.mem.registers[i]);
+                    }
+                    self.advance_i_after_bulk(second_nibble);
+                }
+                0x65 => {
+                    for i in 0..=second_nibble as usize {
+                        self.mem.registers[i] = self.mem.read(i)
+                    }
+                    self.advance_i_after_bulk(second_nibble);
+                }
+                0x33 => {
+                    let x = self.mem.registers[second_nibble as usize];
+                    let x1 = x / 100;
+                    let x2 = (x % 100) / 10;
+                    let x3 = x % 10;
+                    for (offset, digit) in [x1, x2, x3].into_iter().enumerate() {
+                        self.mem.write(offset, digit);
+                    }
+                }
+                0x0A => {
+                    let mut pressed_key = 0u8;
+                    let mut no_pressed_key = true;
+                    'get_key_loop: for i in 0..16u8 {
+                        if self.input[i as usize] {
+                            no_pressed_key = false;
+                            pressed_key = i;
+                            break 'get_key_loop;
+                        }
+                    }
+                    if no_pressed_key {
+                        self.mem.pc -= 2;
+                    } else {
+                        self.mem.registers[second_nibble as usize] = pressed_key;
+                    }
+                }
+                0x02 if second_nibble == 0 => {
+                    // XO-CHIP: load the audio pattern buffer from I
+                    let mut pattern = [0u8; 16];
+                    for (i, byte) in pattern.iter_mut().enumerate() {
+                        *byte = self.mem.read(i);
+                    }
+                    self.mem.audio_pattern = Some(pattern);
+                }
+                0x3A => self.mem.pitch = self.mem.registers[second_nibble as usize],
+                0x29 => {
+                    let x = self.mem.registers[second_nibble as usize];
+                    let memory_address =
+                        ChipMemory::FONT_ROM_STARTING_MEMORY_LOCATION + (5usize * x as usize);
+                    self.mem.i = memory_address as u16;
+                }
+                _ => return Err(unknown()),
+            },
+
+            _ => return Err(unknown()),
+        }
+
+        Ok(())
+    }
+
+    /// Where FX55 and FX65 leave I after storing or loading V0 to VX.
+    fn advance_i_after_bulk(&mut self, x: u8) {
+        if self.quirks.memory_increment_by_x {
+            self.mem.i = self.mem.i.wrapping_add(x as u16);
+        } else if !self.quirks.memory_leave_i_unchanged {
+            self.mem.i = self.mem.i.wrapping_add(x as u16 + 1);
+        }
+    }
+}
+
+pub struct ChipMemory {
+    pub ram: [u8; 4096],
+    pub pc: u16,
+    pub i: u16,
+    pub stack: [u16; 32],
+    pub stack_ptr: usize,
+    pub timers: chip_timers::ChipTimers,
+    pub registers: [u8; 16],
+    /// XO-CHIP audio pattern buffer, `None` until F002 loads one.
+    pub audio_pattern: Option<[u8; 16]>,
+    /// XO-CHIP pitch register, set by FX3A.
+    pub pitch: u8,
+    /// The RAM instructions read and write relative to I, logged while
+    /// watchpoints are set.
+    pub(crate) accesses: Option<Vec<MemoryAccess>>,
+}
+
+impl ChipMemory {
+    pub const ROM_STARTING_MEMORY_LOCATION: usize = 0x200;
+    const FONT_DATA: [u8; 0x10 * 5usize] = [
+        0xF0, 0x90, 0x90, 0x90, 0xF0, //0
+        0x20, 0x60, 0x20, 0x20, 0x70, // 1
+        0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+        0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+        0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+        0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+        0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+        0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+        0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+        0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+        0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+        0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+        0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+        0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+        0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+        0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+    ];
+    pub const FONT_ROM_STARTING_MEMORY_LOCATION: usize = 0x50;
+    pub fn new() -> Self {
+        let mut ram = [0u8; 4096];
+        let font_start = Self::FONT_ROM_STARTING_MEMORY_LOCATION;
+        ram[font_start..font_start + Self::FONT_DATA.len()].copy_from_slice(&Self::FONT_DATA);
+
+        Self {
+            ram,
+            pc: 0u16,
+            i: 0u16,
+            stack: [0u16; 32],
+            stack_ptr: 0,
+            timers: chip_timers::ChipTimers::new(),
+            registers: [0u8; 16],
+            audio_pattern: None,
+            pitch: AudioPattern::DEFAULT_PITCH,
+            accesses: None,
+        }
+    }
+
+    /// The address `offset` bytes past I, wrapping around the end of RAM.
+    fn ram_index(&self, offset: usize) -> usize {
+        (self.i as usize + offset) % self.ram.len()
+    }
+
+    /// Reads the byte `offset` past I for an instruction.
+    fn read(&mut self, offset: usize) -> u8 {
+        let address = self.ram_index(offset);
+        let value = self.ram[address];
+        self.log_access(address, value, Access::Read);
+        value
+    }
+    /// Writes the byte `offset` past I for an instruction.
+    fn write(&mut self, offset: usize, value: u8) {
+        let address = self.ram_index(offset);
+        self.ram[address] = value;
+        self.log_access(address, value, Access::Write);
+    }
+    fn log_access(&mut self, address: usize, value: u8, access: Access) {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(MemoryAccess {
+                address: address as u16,
+                value,
+                access,
+            });
+        }
+    }
+
+    pub(crate) fn get_instruction(&mut self) -> Result<instruction::Instruction, String> {
+        let pc = self.pc as usize;
+        if pc + 1 >= self.ram.len() {
+            return Err(format!("Ran off the end of memory at {:#06x}", pc));
+        }
+        let k = [self.ram[pc], self.ram[pc + 1]];
+        let ret_val = instruction::Instruction::new(k);
+        // println!("Instruction loaded: {:02x?}", k);
+        self.pc += 2;
+        Ok(ret_val)
+    }
+}
+
+impl Default for ChipMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_chip_clk() {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        // v0 := 5, v1 := 0, i := hex v2, then the 0 drawn twice at (5, 0)
+        machine
+            .load_rom_bytes(&[0x60, 0x05, 0x61, 0x00, 0xF2, 0x29, 0xD0, 0x15, 0xD0, 0x15])
+            .unwrap();
+        for _ in 0..4 {
+            machine.chip_clk().unwrap();
+        }
+        let lit = |machine: &ChipMachine, x, y| machine.display.get_display_at_location(x, y);
+        assert_eq!(
+            machine.mem.i as usize,
+            ChipMemory::FONT_ROM_STARTING_MEMORY_LOCATION
+        );
+        assert!((5..9).all(|x| lit(&machine, x, 0).unwrap()));
+        assert!(!lit(&machine, 9, 0).unwrap());
+        assert!(lit(&machine, 5, 1).unwrap() && !lit(&machine, 6, 1).unwrap());
+        assert_eq!(machine.mem.registers[0xF], 0);
+
+        // Drawing it again erases it and reports the collision
+        machine.chip_clk().unwrap();
+        assert!(machine.display.pixels().iter().all(|&pixel| !pixel));
+        assert_eq!(machine.mem.registers[0xF], 1);
+        assert_eq!(machine.mem.pc, 0x20A);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        // 0x200: V0 += 1, 0x202: jump to 0x200
+        machine.load_rom_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        machine.breakpoints.insert(0x202);
+        assert!(!machine.run_cycles(10).unwrap());
+        assert_eq!(machine.mem.pc, 0x202);
+        // Resuming runs past the breakpoint it stopped at, then stops at it
+        // again on the next time round
+        assert!(!machine.run_cycles(10).unwrap());
+        assert_eq!(machine.mem.registers[0], 2);
+        machine.toggle_breakpoint(0x202);
+        assert!(machine.run_cycles(10).unwrap());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        // 0x200: I := 0x3A0, 0x202: V0 += 1, 0x204: save V0, 0x206: jump 0x202
+        machine
+            .load_rom_bytes(&[0xA3, 0xA0, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x02])
+            .unwrap();
+        machine.quirks.memory_leave_i_unchanged = true;
+        machine.break_at("write 3a0").unwrap();
+        assert!(!machine.run_cycles(10).unwrap());
+        assert_eq!(machine.mem.pc, 0x206);
+        assert_eq!(machine.stop_reason(), Some("Wrote 0x01 to 0x03a0"));
+
+        machine.clear_breakpoints();
+        machine.break_at("if V0 == 3").unwrap();
+        machine.break_at("202 if V0 == 5").unwrap();
+        assert!(!machine.run_cycles(20).unwrap());
+        assert_eq!(machine.mem.registers[0], 3);
+        assert_eq!(machine.stop_reason(), Some("Condition met, V0 == 3"));
+        // The condition is still true, but only stops again once it has
+        // been false
+        assert!(!machine.run_cycles(20).unwrap());
+        assert_eq!((machine.mem.pc, machine.mem.registers[0]), (0x202, 5));
+
+        machine.clear_breakpoints();
+        machine.break_at("change vf").unwrap();
+        assert!(machine.run_cycles(20).unwrap());
+        assert!(machine.break_at("change pc").is_err());
+    }
+}
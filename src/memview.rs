@@ -0,0 +1,180 @@
+use crate::machine::ChipMemory;
+use crate::watchpoints::Register;
+
+/// How an address in the hex dump is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mark {
+    None,
+    /// A return address on the call stack.
+    Stack,
+    I,
+    Pc,
+    Cursor,
+}
+
+/// The registers in the order the editor steps through them.
+pub const REGISTERS: [Register; 21] = [
+    Register::V(0x0),
+    Register::V(0x1),
+    Register::V(0x2),
+    Register::V(0x3),
+    Register::V(0x4),
+    Register::V(0x5),
+    Register::V(0x6),
+    Register::V(0x7),
+    Register::V(0x8),
+    Register::V(0x9),
+    Register::V(0xA),
+    Register::V(0xB),
+    Register::V(0xC),
+    Register::V(0xD),
+    Register::V(0xE),
+    Register::V(0xF),
+    Register::Pc,
+    Register::I,
+    Register::Sp,
+    Register::Dt,
+    Register::St,
+];
+
+/// A hex dump of RAM that can be edited in place, along with the
+/// registers.  Typed hex digits shift into the byte or register under the
+/// cursor.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryView {
+    /// The byte being edited.
+    pub cursor: u16,
+    /// The first address shown.
+    top: u16,
+    /// Which of `REGISTERS` is being edited, instead of RAM.
+    register: Option<usize>,
+    /// Digits typed into the current byte or register so far.
+    typed: u8,
+}
+
+impl MemoryView {
+    pub const ROWS: u16 = 16;
+    pub const BYTES_PER_ROW: u16 = 16;
+    const RAM_SIZE: i32 = 4096;
+
+    /// The register being edited, if not RAM.
+    pub fn register(&self) -> Option<Register> {
+        self.register.map(|n| REGISTERS[n])
+    }
+    /// Switches between editing RAM and the registers.
+    pub fn toggle_registers(&mut self) {
+        self.register = match self.register {
+            Some(_) => None,
+            None => Some(0),
+        };
+        self.typed = 0;
+    }
+
+    /// Moves the cursor `delta` bytes through RAM, or `delta` registers
+    /// when editing those.
+    pub fn move_cursor(&mut self, delta: i32) {
+        self.typed = 0;
+        match &mut self.register {
+            Some(n) => *n = (*n as i32 + delta).rem_euclid(REGISTERS.len() as i32) as usize,
+            None => self.go_to((self.cursor as i32 + delta).rem_euclid(Self::RAM_SIZE) as u16),
+        }
+    }
+    /// Puts the cursor on `address`, scrolling it into view.
+    pub fn go_to(&mut self, address: u16) {
+        self.typed = 0;
+        self.register = None;
+        self.cursor = address % Self::RAM_SIZE as u16;
+        let row = self.cursor - self.cursor % Self::BYTES_PER_ROW;
+        let shown = Self::ROWS * Self::BYTES_PER_ROW;
+        if row < self.top {
+            self.top = row;
+        } else if row >= self.top + shown {
+            self.top = row + Self::BYTES_PER_ROW - shown;
+        }
+    }
+
+    /// Shifts a hex digit into the byte or register under the cursor.  A
+    /// byte moves the cursor on once both its digits are typed.
+    pub fn type_digit(&mut self, mem: &mut ChipMemory, digit: u8) {
+        let digit = digit as u16 & 0xF;
+        match self.register() {
+            Some(register) => {
+                let old = if self.typed == 0 {
+                    0
+                } else {
+                    register.value(mem)
+                };
+                register.set(mem, old << 4 | digit);
+                self.typed = (self.typed + 1) % register.digits();
+            }
+            None => {
+                let byte = &mut mem.ram[self.cursor as usize];
+                let old = if self.typed == 0 { 0 } else { *byte };
+                *byte = old << 4 | digit as u8;
+                self.typed += 1;
+                if self.typed == 2 {
+                    self.move_cursor(1);
+                }
+            }
+        }
+    }
+
+    /// The first address of each row shown.
+    pub fn rows(&self) -> impl Iterator<Item = u16> {
+        let top = self.top;
+        (0..Self::ROWS).map(move |row| top + row * Self::BYTES_PER_ROW)
+    }
+
+    /// How to show the byte at `address`: the cursor over the PC over I
+    /// over the call stack.
+    pub fn mark(&self, mem: &ChipMemory, address: u16) -> Mark {
+        let depth = mem.stack_ptr.min(mem.stack.len());
+        if self.register.is_none() && address == self.cursor {
+            Mark::Cursor
+        } else if address == mem.pc || address == mem.pc.wrapping_add(1) {
+            Mark::Pc
+        } else if address == mem.i {
+            Mark::I
+        } else if mem.stack[..depth].contains(&address) {
+            Mark::Stack
+        } else {
+            Mark::None
+        }
+    }
+}
+
+/// A byte as a row of sprite pixels.
+pub fn sprite_row(byte: u8) -> String {
+    (0..8)
+        .map(|bit| if byte << bit & 0x80 != 0 { '█' } else { '·' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_editing() {
+        let mut mem = ChipMemory::new();
+        let mut view = MemoryView::default();
+        view.go_to(0x3FF);
+        assert_eq!(view.rows().next(), Some(0x300));
+        for digit in [0xA, 0xB, 0xC] {
+            view.type_digit(&mut mem, digit);
+        }
+        assert_eq!(mem.ram[0x3FF..0x401], [0xAB, 0x0C]);
+        assert_eq!(view.cursor, 0x400);
+        assert_eq!(view.rows().last(), Some(0x400));
+        assert_eq!(view.mark(&mem, 0x400), Mark::Cursor);
+
+        view.toggle_registers();
+        view.move_cursor(-4);
+        assert_eq!(view.register(), Some(Register::I));
+        for digit in [0x3, 0xA, 0x0] {
+            view.type_digit(&mut mem, digit);
+        }
+        assert_eq!(mem.i, 0x3A0);
+        assert_eq!(view.mark(&mem, 0x3A0), Mark::I);
+        assert_eq!(sprite_row(0xA5), "█·█··█·█");
+    }
+}
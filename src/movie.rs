@@ -0,0 +1,395 @@
+use crate::difftest::MachineState;
+use crate::json::Json;
+use crate::machine::ChipMachine;
+use crate::quirks::Quirks;
+use crate::rng::Generator;
+use crate::screenshot;
+use crate::sha1::sha1_hex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The keypad as a mask, key N in bit N.
+pub fn keypad(input: &[bool; 16]) -> u16 {
+    input
+        .iter()
+        .enumerate()
+        .fold(0, |keys, (key, &down)| keys | (down as u16) << key)
+}
+
+/// A SHA-1 over the RAM, registers, stack, timers and screen.
+pub fn checksum(machine: &ChipMachine) -> String {
+    let mem = &machine.mem;
+    let mut bytes = mem.ram.to_vec();
+    bytes.extend_from_slice(&mem.registers);
+    bytes.extend_from_slice(&mem.pc.to_be_bytes());
+    bytes.extend_from_slice(&mem.i.to_be_bytes());
+    for address in mem.stack {
+        bytes.extend_from_slice(&address.to_be_bytes());
+    }
+    bytes.extend([mem.stack_ptr as u8, mem.timers.delay, mem.timers.sound]);
+    bytes.extend(screenshot::pack_pixels(machine.display.pixels()));
+    sha1_hex(&bytes)
+}
+
+/// A keypad change, taking effect before an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Input {
+    pub frame: u32,
+    /// Instructions into the frame.
+    pub cycle: u32,
+    pub keys: u16,
+}
+
+/// A session from the ROM loading, as what it needs to run the same way
+/// again: the RNG and its seed, the settings, and the keypad.
+///
+/// Saved as JSON, with `frames` as `COUNTxCYCLES` runs of frames that ran
+/// that many instructions (fewer than the tickrate while paused or stopped
+/// at a breakpoint) and `inputs` as `FRAME/CYCLE KEYS`, the keys in hex.
+/// The state at the end of the last frame, before the timers tick, is kept
+/// to check a replay against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_sha1: String,
+    pub generator: Generator,
+    pub seed: u32,
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    /// Instructions run in each frame.
+    pub frames: Vec<u32>,
+    pub inputs: Vec<Input>,
+    pub state: String,
+    pub checksum: String,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Json::parse(&text)
+            .and_then(|json| Self::from_json(&json))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_json().to_pretty_string())
+            .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let string = |key: &str| {
+            json.get(key)
+                .and_then(Json::as_str)
+                .ok_or_else(|| format!("Missing {}", key))
+        };
+        let number = |key: &str| {
+            json.get(key)
+                .and_then(Json::as_f64)
+                .map(|n| n as u32)
+                .ok_or_else(|| format!("Missing {}", key))
+        };
+        let mut quirks = Quirks::default();
+        for (name, value) in json.get("quirks").map(Json::members).unwrap_or(&[]) {
+            let value = value
+                .as_bool()
+                .ok_or_else(|| format!("Quirk {} should be true or false", name))?;
+            quirks.set(name, value)?;
+        }
+        let mut frames = Vec::new();
+        for run in json.get("frames").map(Json::items).unwrap_or(&[]) {
+            let (count, cycles): (usize, u32) = run
+                .as_str()
+                .and_then(|run| run.split_once('x'))
+                .and_then(|(count, cycles)| Some((count.parse().ok()?, cycles.parse().ok()?)))
+                .ok_or_else(|| format!("Expected COUNTxCYCLES, not {:?}", run))?;
+            frames.extend(std::iter::repeat_n(cycles, count));
+        }
+        let mut inputs = Vec::new();
+        for input in json.get("inputs").map(Json::items).unwrap_or(&[]) {
+            let parsed = input.as_str().and_then(|input| {
+                let (at, keys) = input.split_once(' ')?;
+                let (frame, cycle) = at.split_once('/')?;
+                Some(Input {
+                    frame: frame.parse().ok()?,
+                    cycle: cycle.parse().ok()?,
+                    keys: u16::from_str_radix(keys, 16).ok()?,
+                })
+            });
+            inputs
+                .push(parsed.ok_or_else(|| format!("Expected FRAME/CYCLE KEYS, not {:?}", input))?);
+        }
+        Ok(Self {
+            rom_sha1: string("rom")?.to_string(),
+            generator: match json.get("rng") {
+                Some(rng) => rng.as_str().ok_or("The rng must be a string")?.parse()?,
+                None => Generator::default(),
+            },
+            seed: number("seed")?,
+            cycles_per_frame: number("tickrate")?,
+            quirks,
+            frames,
+            inputs,
+            state: string("state")?.to_string(),
+            checksum: string("checksum")?.to_string(),
+        })
+    }
+
+    pub fn to_json(&self) -> Json {
+        let quirks = Quirks::NAMES
+            .iter()
+            .map(|&name| (name.to_string(), Json::Bool(self.quirks.get(name).unwrap())))
+            .collect();
+        let mut frames: Vec<(usize, u32)> = Vec::new();
+        for &cycles in &self.frames {
+            match frames.last_mut() {
+                Some((count, last)) if *last == cycles => *count += 1,
+                _ => frames.push((1, cycles)),
+            }
+        }
+        let frames = frames
+            .iter()
+            .map(|(count, cycles)| Json::String(format!("{}x{}", count, cycles)))
+            .collect();
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|i| Json::String(format!("{}/{} {:04x}", i.frame, i.cycle, i.keys)))
+            .collect();
+        Json::Object(vec![
+            ("rom".to_string(), Json::String(self.rom_sha1.clone())),
+            ("rng".to_string(), Json::String(self.generator.to_string())),
+            ("seed".to_string(), Json::Number(self.seed as f64)),
+            (
+                "tickrate".to_string(),
+                Json::Number(self.cycles_per_frame as f64),
+            ),
+            ("quirks".to_string(), Json::Object(quirks)),
+            ("frames".to_string(), Json::Array(frames)),
+            ("inputs".to_string(), Json::Array(inputs)),
+            ("state".to_string(), Json::String(self.state.clone())),
+            ("checksum".to_string(), Json::String(self.checksum.clone())),
+        ])
+    }
+}
+
+/// Records a movie of the session, from just after the ROM loads.  The
+/// frontend tells it about the keypad before running instructions and
+/// about each frame before the timers tick.
+pub struct MovieRecorder {
+    path: PathBuf,
+    movie: Movie,
+    keys: u16,
+}
+
+impl MovieRecorder {
+    /// Starts recording `machine`, restarting its RNG from its seed so a
+    /// replay can start it the same way.
+    pub fn start(path: &Path, machine: &mut ChipMachine) -> Self {
+        let seed = machine.rng_seed();
+        machine.seed_rng(seed);
+        Self {
+            path: path.to_path_buf(),
+            movie: Movie {
+                rom_sha1: machine.rom_sha1().to_string(),
+                generator: machine.generator(),
+                seed,
+                cycles_per_frame: machine.cycles_per_frame,
+                quirks: machine.quirks,
+                frames: Vec::new(),
+                inputs: Vec::new(),
+                state: String::new(),
+                checksum: String::new(),
+            },
+            keys: 0,
+        }
+    }
+
+    /// Notes the keypad if it changed, before instructions run.
+    pub fn input(&mut self, machine: &ChipMachine) {
+        let keys = keypad(&machine.input);
+        if keys != self.keys {
+            self.keys = keys;
+            self.movie.inputs.push(Input {
+                frame: self.movie.frames.len() as u32,
+                cycle: machine.cycles_this_frame(),
+                keys,
+            });
+        }
+    }
+
+    /// Notes the end of a frame, before the timers tick.
+    pub fn frame(&mut self, machine: &ChipMachine) {
+        self.movie.frames.push(machine.cycles_this_frame());
+        self.movie.state = MachineState::capture(machine).to_string();
+        self.movie.checksum = checksum(machine);
+    }
+
+    /// Writes the movie out, up to the last whole frame.
+    pub fn finish(mut self) -> Result<(), String> {
+        let frames = self.movie.frames.len() as u32;
+        self.movie.inputs.retain(|input| input.frame < frames);
+        self.movie.save(&self.path)?;
+        println!(
+            "Recorded {} frames to {}",
+            self.movie.frames.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Replays a movie into the machine a frame at a time, then checks it
+/// ended up where the recording did.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    input: usize,
+    /// The keypad as of the last input replayed.
+    keys: u16,
+}
+
+impl MoviePlayer {
+    /// Sets up `machine`, with the movie's ROM just loaded, to replay it.
+    pub fn start(movie: Movie, machine: &mut ChipMachine) -> Result<Self, String> {
+        if movie.rom_sha1 != machine.rom_sha1() {
+            return Err(format!(
+                "The movie was recorded with a different ROM (SHA-1 {})",
+                movie.rom_sha1
+            ));
+        }
+        if !movie.generator.available() {
+            return Err(
+                "The movie was recorded with the vip generator, which needs --vip-interpreter"
+                    .to_string(),
+            );
+        }
+        machine.set_generator(movie.generator);
+        machine.seed_rng(movie.seed);
+        machine.cycles_per_frame = movie.cycles_per_frame;
+        machine.quirks = movie.quirks;
+        machine.input = [false; 16];
+        Ok(Self {
+            movie,
+            frame: 0,
+            input: 0,
+            keys: 0,
+        })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.movie.frames.len()
+    }
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// Runs the next frame's instructions as recorded, pressing keys when
+    /// they were pressed whatever the frontend has put on the keypad.
+    /// Breakpoints don't stop a replay, and the timers are left for the
+    /// frontend to tick.
+    pub fn run_frame(&mut self, machine: &mut ChipMachine) -> Result<(), String> {
+        let Some(&cycles) = self.movie.frames.get(self.frame) else {
+            return Ok(());
+        };
+        for cycle in 0..cycles {
+            while let Some(input) = self.movie.inputs.get(self.input) {
+                if (input.frame as usize, input.cycle) > (self.frame, cycle) {
+                    break;
+                }
+                self.keys = input.keys;
+                self.input += 1;
+            }
+            for (key, down) in machine.input.iter_mut().enumerate() {
+                *down = self.keys >> key & 1 != 0;
+            }
+            machine.chip_clk()?;
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    /// Compares the machine, after the last frame, with the recording.
+    pub fn verify(&self, machine: &ChipMachine) -> Result<(), String> {
+        if checksum(machine) == self.movie.checksum {
+            return Ok(());
+        }
+        let replayed = MachineState::capture(machine).to_string();
+        let mut report = format!(
+            "Replay diverged after {} frames\n  recorded: {}\n  replayed: {}",
+            self.frame, self.movie.state, replayed
+        );
+        if replayed == self.movie.state {
+            report.push_str("\n  (the registers and screen match; RAM differs)");
+        }
+        Err(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::machine::ChipMemory;
+    #[test]
+    fn test_movie() {
+        // Counts key 5 presses in v1 and draws random digits
+        let program = assemble(
+            "
+            : main
+              v0 := 5
+              if v0 key then v1 += 1
+              v2 := random 0xF
+              i := hex v2
+              clear
+              sprite v1 v1 5
+              v3 := 1
+              delay := v3
+              loop
+                v3 := delay
+                if v3 != 0 then
+              again
+              jump main
+            ",
+        )
+        .unwrap();
+        let new_machine = || {
+            let mut machine = ChipMachine::new(ChipMemory::new());
+            machine.load_rom_bytes(&program.bytes).unwrap();
+            machine
+        };
+        let mut machine = new_machine();
+        let mut recorder = MovieRecorder::start(Path::new("unused"), &mut machine);
+        for frame in 0..40 {
+            machine.input[5] = frame % 7 < 3;
+            recorder.input(&machine);
+            // As if paused for a few frames, or stopped partway through one
+            let cycles = match frame {
+                10..=12 => 0,
+                20 => 5,
+                _ => machine.cycles_per_frame,
+            };
+            machine.run_cycles(cycles).unwrap();
+            recorder.frame(&machine);
+            machine.tick_timers();
+        }
+        assert!(machine.mem.registers[1] > 0);
+        let json = recorder.movie.to_json().to_pretty_string();
+        let movie = Movie::from_json(&Json::parse(&json).unwrap()).unwrap();
+        assert_eq!(movie, recorder.movie);
+
+        let replay = |movie: Movie| {
+            let mut machine = new_machine();
+            let mut player = MoviePlayer::start(movie, &mut machine).unwrap();
+            player.run_frame(&mut machine).unwrap();
+            while !player.finished() {
+                machine.tick_timers();
+                player.run_frame(&mut machine).unwrap();
+            }
+            player.verify(&machine)
+        };
+        replay(movie.clone()).unwrap();
+        // A different seed draws different digits
+        let seed = movie.seed ^ 1;
+        assert!(replay(Movie { seed, ..movie }).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::audio::BeepConfig;
+use crate::phosphor::Persistence;
+use crate::quirks::{Platform, Quirks, PLATFORMS};
+use crate::rom_config::{parse_colour, RomSettings};
+use crate::screenshot::Palette;
+use crate::trace::TraceFormat;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+const DEFAULT_ROM_DIR: &str = "roms";
+const USAGE: &str = "Usage: sdl-test [ROM] [--tui] [--record PATH] [--wav PATH] [--frames N]
+                [ROM settings] [beeper options]
+       sdl-test asm SOURCE [-o OUTPUT] [-s SYMBOLS]
+       sdl-test diff REFERENCE ROM [ROM settings]
+
+Without a ROM, the window opens on a list of the ROMs in the ROM directory.
+A ROM ending in .8o is Octo source, assembled as it is loaded; the asm
+command writes it out as a binary instead (next to the source by default),
+and -s writes its labels in the form --symbols reads.  diff runs a ROM
+against a trace recorded in the --trace-format state form, stopping at the
+first instruction where the two disagree.
+
+  --rom-dir DIR   Where to look for ROMs (default roms)
+  --tui           Draw to the terminal instead of opening a window; Tab
+                  shows a hex editor for RAM and the registers, then a
+                  viewer for sprites at I or found in the ROM
+  --persistence M Hide the flicker of redrawn sprites in the window: off,
+                  blend (keep the last frame's pixels lit) or fade:N (fade
+                  them out over N frames; fade alone is fade:8)
+  --record PATH   Record the session to PATH (.gif, or a raw frame dump
+                  with a .wav of the beeper for any other extension)
+  --wav PATH      Write the beeper to a WAV in step with emulated time
+                  instead of playing it
+  --frames N      Run headless for N frames instead of opening a window
+  --record-movie PATH
+                  Record the keypad, with the RNG seed and settings, from
+                  the ROM loading on, to replay exactly with --play-movie
+  --play-movie PATH
+                  Replay a recorded movie headless, or in the terminal
+                  with --tui, and check it ends in the recorded state
+  --trace PATH    Log every instruction run to PATH, or stdout for -
+  --trace-range A-B
+                  Only log instructions between these addresses (hex)
+  --trace-limit N Stop logging after N lines
+  --trace-format F
+                  text, or state for the portable form diff compares
+                  (default text)
+  --watch         Reload the ROM whenever its file changes
+  --break ADDR    Pause before running the instruction at ADDR, a label
+                  or a hex address; may be given more than once.  ADDR if
+                  COND only pauses there when COND holds
+  --break-if COND Pause wherever COND becomes true, e.g.
+                  V3==0x10&&I>0x300; [ADDR] is a byte of RAM
+  --break-read A[-B], --break-write A[-B]
+                  Pause after an instruction reads or writes RAM there
+  --break-change R
+                  Pause after an instruction changes a register: V0-VF,
+                  I, SP, DT or ST
+  --symbols PATH  Labels for the debugger, as lines of ADDR LABEL or a
+                  JSON object (default: ROM.sym, or the labels of an
+                  assembled .8o)
+  --script PATH   Run a Rhai script against the ROM once it loads; it can
+                  read and write RAM and the registers, hold keys down,
+                  run frames, take screenshots, and set functions to call
+                  every frame or instruction.  With --frames 0 only the
+                  script runs
+  --gdb PORT      Wait for GDB to attach over its remote protocol, on a
+                  local TCP port or a Unix socket at a path; the ROM
+                  only runs while the debugger continues it
+  --keep-breakpoints
+                  Keep breakpoints set during the session when the ROM is
+                  reloaded, instead of going back to those given here
+
+Beeper options:
+  --tone HZ       Frequency of the beep (default 440)
+  --waveform W    square, sine, triangle or noise (default square)
+  --duty F        Fraction of the period a square wave is high (default 0.5)
+  --volume F      Volume from 0 to 1 (default 0.1)
+  --mute          Don't open a sound device; beeps are shown on screen
+In a window, [ and ] change the tone, ; and ' the duty, - and = the volume,
+G the waveform, and B mutes.
+
+ROM settings, which override the chip-8-database and saved settings:
+  --platform ID   originalChip8, hybridVIP, modernChip8, chip8x, chip48,
+                  superchip1, superchip, megachip8 or xochip
+  --tickrate N    Instructions per frame
+  --rng GEN       How CXNN makes random numbers: xorshift, vip (the COSMAC
+                  VIP's, see --vip-interpreter) or rand (C's example
+                  rand()); the platform picks by default
+  --seed N        Seed CXNN with N instead of the clock, so runs repeat
+  --quirk Q=BOOL  Turn a quirk on or off: shift, memoryIncrementByX,
+                  memoryLeaveIUnchanged, wrap, jump, vblank or logic
+  --colors C,C    Unlit and lit pixel colours, as #rrggbb
+  --save-config   Remember these settings for this ROM
+  --database DIR  Where to find the chip-8-database (default
+                  ~/.config/chipn80/chip-8-database)
+  --vip-interpreter PATH
+                  The COSMAC VIP's 512-byte CHIP-8 interpreter, which the
+                  vip generator reads from (default
+                  ~/.config/chipn80/vip-interpreter.bin)";
+
+/// Settings picked on the command line.
+pub struct Options {
+    pub rom_path: Option<String>,
+    pub rom_dir: PathBuf,
+    pub tui: bool,
+    pub record: Option<PathBuf>,
+    pub wav: Option<PathBuf>,
+    pub frames: Option<u32>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub watch: bool,
+    pub persistence: Persistence,
+    /// Breakpoints and watchpoints in the forms `ChipMachine::break_at` takes,
+    /// resolved once the ROM's symbols are loaded.
+    pub breakpoints: Vec<String>,
+    pub keep_breakpoints: bool,
+    /// Where to listen for a debugger.
+    pub gdb: Option<String>,
+    pub script: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+    pub trace: Option<String>,
+    pub trace_range: Option<RangeInclusive<u16>>,
+    pub trace_limit: Option<u64>,
+    pub trace_format: TraceFormat,
+    pub beep: BeepConfig,
+    /// Keep the beeper off the sound device.  Files it is rendered to still
+    /// have it.
+    pub mute: bool,
+    /// Settings for the ROM given on the command line.
+    pub rom_settings: RomSettings,
+    pub save_config: bool,
+    pub database: Option<PathBuf>,
+    pub vip_interpreter: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut rom_path = None;
+        let mut rom_dir = PathBuf::from(DEFAULT_ROM_DIR);
+        let mut tui = false;
+        let mut record = None;
+        let mut wav = None;
+        let mut frames = None;
+        let mut record_movie = None;
+        let mut play_movie = None;
+        let mut watch = false;
+        let mut persistence = Persistence::Off;
+        let mut breakpoints = Vec::new();
+        let mut keep_breakpoints = false;
+        let mut gdb = None;
+        let mut script = None;
+        let mut symbols = None;
+        let mut trace = None;
+        let mut trace_range = None;
+        let mut trace_limit = None;
+        let mut trace_format = TraceFormat::Text;
+        let mut beep = BeepConfig::default();
+        let mut mute = false;
+        let mut rom_settings = RomSettings::default();
+        let mut save_config = false;
+        let mut database = None;
+        let mut vip_interpreter = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rom-dir" => rom_dir = PathBuf::from(value(&mut args, &arg)?),
+                "--tui" => tui = true,
+                "--record" => record = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--wav" => wav = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--frames" => {
+                    let n = value(&mut args, &arg)?;
+                    frames = Some(
+                        n.parse()
+                            .map_err(|_| format!("Invalid frame count {}", n))?,
+                    );
+                }
+                "--record-movie" => record_movie = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--play-movie" => play_movie = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--watch" => watch = true,
+                "--persistence" => persistence = value(&mut args, &arg)?.parse()?,
+                "--break" => breakpoints.push(value(&mut args, &arg)?),
+                "--break-if" => breakpoints.push(format!("if {}", value(&mut args, &arg)?)),
+                "--break-read" => breakpoints.push(format!("read {}", value(&mut args, &arg)?)),
+                "--break-write" => breakpoints.push(format!("write {}", value(&mut args, &arg)?)),
+                "--break-change" => breakpoints.push(format!("change {}", value(&mut args, &arg)?)),
+                "--keep-breakpoints" => keep_breakpoints = true,
+                "--gdb" => gdb = Some(value(&mut args, &arg)?),
+                "--script" => script = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--symbols" => symbols = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--trace" => trace = Some(value(&mut args, &arg)?),
+                "--trace-range" => {
+                    let range = value(&mut args, &arg)?;
+                    let (start, end) = range
+                        .split_once('-')
+                        .ok_or_else(|| format!("Expected START-END, not {}", range))?;
+                    trace_range = Some(parse_address(start)?..=parse_address(end)?);
+                }
+                "--trace-format" => trace_format = value(&mut args, &arg)?.parse()?,
+                "--trace-limit" => {
+                    let n = value(&mut args, &arg)?;
+                    trace_limit = Some(n.parse().map_err(|_| format!("Invalid line count {}", n))?);
+                }
+                "--tone" => beep.frequency = number(&mut args, &arg, "above 0", |n| n > 0.0)?,
+                "--waveform" => beep.waveform = value(&mut args, &arg)?.parse()?,
+                "--duty" => {
+                    beep.duty = number(&mut args, &arg, "0 to 1", |n| (0.0..=1.0).contains(&n))?
+                }
+                "--volume" => {
+                    beep.volume = number(&mut args, &arg, "0 to 1", |n| (0.0..=1.0).contains(&n))?
+                }
+                "--mute" => mute = true,
+                "--platform" => {
+                    let id = value(&mut args, &arg)?;
+                    if Platform::find(&id).is_none() {
+                        let ids: Vec<_> = PLATFORMS.iter().map(|p| p.id).collect();
+                        return Err(format!("Unknown platform {}, try {}", id, ids.join(", ")));
+                    }
+                    rom_settings.platforms = vec![id];
+                }
+                "--tickrate" => {
+                    let n = value(&mut args, &arg)?;
+                    rom_settings.tickrate =
+                        Some(n.parse().map_err(|_| format!("Invalid tickrate {}", n))?);
+                }
+                "--rng" => rom_settings.rng = Some(value(&mut args, &arg)?.parse()?),
+                "--seed" => {
+                    let n = value(&mut args, &arg)?;
+                    rom_settings.seed = Some(n.parse().map_err(|_| format!("Invalid seed {}", n))?);
+                }
+                "--quirk" => {
+                    let quirk = value(&mut args, &arg)?;
+                    let (name, on) = quirk
+                        .split_once('=')
+                        .and_then(|(name, on)| Some((name, on.parse().ok()?)))
+                        .ok_or_else(|| {
+                            format!("Expected NAME=true or NAME=false, not {}", quirk)
+                        })?;
+                    Quirks::default().set(name, on)?;
+                    rom_settings.quirks.push((name.to_string(), on));
+                }
+                "--colors" => {
+                    let colors = value(&mut args, &arg)?;
+                    let palette = colors
+                        .split_once(',')
+                        .and_then(|(off, on)| {
+                            Some(Palette {
+                                off: parse_colour(off)?,
+                                on: parse_colour(on)?,
+                            })
+                        })
+                        .ok_or_else(|| format!("Expected #rrggbb,#rrggbb, not {}", colors))?;
+                    rom_settings.palette = Some(palette);
+                }
+                "--save-config" => save_config = true,
+                "--database" => database = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--vip-interpreter" => {
+                    vip_interpreter = Some(PathBuf::from(value(&mut args, &arg)?))
+                }
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n{}", arg, USAGE))
+                }
+                _ => rom_path = Some(arg),
+            }
+        }
+        if frames.is_some()
+            && record.is_none()
+            && wav.is_none()
+            && trace.is_none()
+            && script.is_none()
+            && record_movie.is_none()
+            && play_movie.is_none()
+        {
+            return Err(format!(
+                "--frames needs --record, --wav, --trace, --script or a movie\n{}",
+                USAGE
+            ));
+        }
+        if record_movie.is_some() && play_movie.is_some() {
+            return Err("A movie can't be recorded while another plays".to_string());
+        }
+        Ok(Self {
+            rom_path,
+            rom_dir,
+            tui,
+            record,
+            wav,
+            frames,
+            record_movie,
+            play_movie,
+            watch,
+            persistence,
+            breakpoints,
+            keep_breakpoints,
+            gdb,
+            script,
+            symbols,
+            trace,
+            trace_range,
+            trace_limit,
+            trace_format,
+            beep,
+            mute,
+            rom_settings,
+            save_config,
+            database,
+            vip_interpreter,
+        })
+    }
+}
+
+impl Options {
+    /// The ROM to run, for everything but the window, which can start
+    /// without one.
+    pub fn rom(&self) -> Result<&str, String> {
+        self.rom_path.as_deref().ok_or_else(|| {
+            format!(
+                "No ROM given; only the window can start without one\n{}",
+                USAGE
+            )
+        })
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} needs a value\n{}", flag, USAGE))
+}
+
+/// Reads a finite number for `flag` that passes `valid`, which `expected`
+/// describes.
+fn number<I: Iterator<Item = String>>(
+    args: &mut I,
+    flag: &str,
+    expected: &str,
+    valid: impl Fn(f32) -> bool,
+) -> Result<f32, String> {
+    let n = value(args, flag)?;
+    n.parse()
+        .ok()
+        .filter(|&n: &f32| n.is_finite() && valid(n))
+        .ok_or_else(|| format!("Invalid number {} for {}, expected {}", n, flag, expected))
+}
+
+/// Reads an address written in hex, with or without a leading `0x`.
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16)
+        .ok()
+        .filter(|&address| (address as usize) < 4096)
+        .ok_or_else(|| format!("Invalid address {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_beep_options() {
+        let parse = |args: &[&str]| Options::parse(args.iter().map(|a| a.to_string()));
+        let options = parse(&["--tone", "220", "--duty", "0.25", "--volume", "1"]).unwrap();
+        assert_eq!(
+            (
+                options.beep.frequency,
+                options.beep.duty,
+                options.beep.volume
+            ),
+            (220.0, 0.25, 1.0)
+        );
+        for (flag, n) in [
+            ("--tone", "0"),
+            ("--tone", "-440"),
+            ("--tone", "inf"),
+            ("--duty", "2"),
+            ("--volume", "5"),
+            ("--volume", "NaN"),
+        ] {
+            let error = parse(&[flag, n]).err().unwrap();
+            assert!(error.contains(flag), "{}", error);
+        }
+    }
+}
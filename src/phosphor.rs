@@ -0,0 +1,134 @@
+use crate::display::DISPLAY_SIZE;
+use std::str::FromStr;
+
+/// How the renderer hides the flicker of sprites that are XOR-erased and
+/// redrawn every frame.  Only what is presented changes; the emulated
+/// framebuffer is left untouched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Persistence {
+    /// Present the framebuffer exactly as it is.
+    Off,
+    /// A pixel is lit if it is lit now or was at the end of the last frame.
+    Blend,
+    /// Pixels that turn off fade out over the given number of frames.
+    Fade(u8),
+}
+
+impl Persistence {
+    pub const DEFAULT_FADE_FRAMES: u8 = 8;
+}
+
+impl FromStr for Persistence {
+    type Err = String;
+
+    /// `off`, `blend`, `fade`, or `fade:N` to fade over N frames.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("Unknown persistence {}, try off, blend or fade:N", s);
+        let lower = s.to_ascii_lowercase();
+        match lower.split_once(':') {
+            None => match lower.as_str() {
+                "off" => Ok(Persistence::Off),
+                "blend" => Ok(Persistence::Blend),
+                "fade" => Ok(Persistence::Fade(Self::DEFAULT_FADE_FRAMES)),
+                _ => Err(unknown()),
+            },
+            Some(("fade", frames)) => frames
+                .parse()
+                .ok()
+                .filter(|&frames| frames > 0)
+                .map(Persistence::Fade)
+                .ok_or_else(|| format!("Invalid fade length {}, expected 1 to 255", frames)),
+            Some(_) => Err(unknown()),
+        }
+    }
+}
+
+/// Per-pixel brightness left behind on the screen between frames.  It moves
+/// on once per 60 Hz frame, however often the screen is presented.
+pub struct Phosphor {
+    mode: Persistence,
+    levels: [u8; DISPLAY_SIZE],
+}
+
+impl Phosphor {
+    pub fn new(mode: Persistence) -> Self {
+        Self {
+            mode,
+            levels: [0u8; DISPLAY_SIZE],
+        }
+    }
+    pub fn mode(&self) -> Persistence {
+        self.mode
+    }
+    /// Ends a frame, with `frame` the framebuffer as it stands at the end of
+    /// it.
+    pub fn advance(&mut self, frame: &[bool; DISPLAY_SIZE]) {
+        for (level, &lit) in self.levels.iter_mut().zip(frame) {
+            *level = match (self.mode, lit) {
+                (_, true) => 255,
+                (Persistence::Fade(frames), false) => {
+                    level.saturating_sub(255u8.div_ceil(frames.max(1)))
+                }
+                (_, false) => 0,
+            };
+        }
+    }
+    /// The brightness of every pixel to present now: full for those lit in
+    /// `frame`, and what earlier frames left behind for the rest.
+    pub fn levels(&self, frame: &[bool; DISPLAY_SIZE]) -> [u8; DISPLAY_SIZE] {
+        let mut levels = [0u8; DISPLAY_SIZE];
+        for (i, &lit) in frame.iter().enumerate() {
+            levels[i] = match (self.mode, lit) {
+                (_, true) => 255,
+                (Persistence::Off, false) => 0,
+                (_, false) => self.levels[i],
+            };
+        }
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_phosphor_fade() {
+        let mut phosphor = Phosphor::new(Persistence::Fade(4));
+        let mut frame = [false; DISPLAY_SIZE];
+        frame[0] = true;
+        phosphor.advance(&frame);
+        assert_eq!(phosphor.levels(&frame)[0], 255);
+        frame[0] = false;
+        // Presenting more often than once a frame doesn't hurry the fade
+        assert_eq!(phosphor.levels(&frame)[0], 255);
+        assert_eq!(phosphor.levels(&frame)[0], 255);
+        let mut last = 255;
+        for _ in 0..4 {
+            phosphor.advance(&frame);
+            let level = phosphor.levels(&frame)[0];
+            assert!(level < last);
+            last = level;
+        }
+        assert_eq!(last, 0);
+        assert_eq!("fade:4".parse(), Ok(Persistence::Fade(4)));
+        assert!("fade:0".parse::<Persistence>().is_err());
+        assert_eq!(
+            "Fade".parse(),
+            Ok(Persistence::Fade(Persistence::DEFAULT_FADE_FRAMES))
+        );
+    }
+    #[test]
+    fn test_phosphor_blend() {
+        let mut phosphor = Phosphor::new(Persistence::Blend);
+        let mut frame = [false; DISPLAY_SIZE];
+        frame[1] = true;
+        phosphor.advance(&frame);
+        // A sprite erased to be redrawn stays lit through the frame
+        frame[1] = false;
+        assert_eq!(phosphor.levels(&frame)[1], 255);
+        assert_eq!(phosphor.levels(&frame)[1], 255);
+        phosphor.advance(&frame);
+        assert_eq!(phosphor.levels(&frame)[1], 0);
+        assert_eq!("blend".parse(), Ok(Persistence::Blend));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::rng::Generator;
+
+/// Behaviours that differ between CHIP-8 interpreters, named as in the
+/// chip-8-database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VX in place instead of copying VY first.
+    pub shift: bool,
+    /// FX55 and FX65 leave I pointing at the last register stored.
+    pub memory_increment_by_x: bool,
+    /// FX55 and FX65 leave I where it was.
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites wrap around the edges of the screen instead of being clipped.
+    pub wrap: bool,
+    /// BNNN jumps to XNN plus VX instead of NNN plus V0.
+    pub jump: bool,
+    /// DXYN waits for the start of the next frame before drawing.
+    pub vblank: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF.
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    /// How this emulator has always behaved, used for ROMs nothing is known
+    /// about.
+    fn default() -> Self {
+        Self {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+/// A platform from the chip-8-database.
+#[derive(Debug)]
+pub struct Platform {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub quirks: Quirks,
+    /// Instructions per 60 Hz frame.
+    pub tickrate: u32,
+    /// How its interpreter makes CXNN's random numbers.
+    pub generator: Generator,
+}
+
+const fn quirks(
+    shift: bool,
+    memory_increment_by_x: bool,
+    memory_leave_i_unchanged: bool,
+    wrap: bool,
+    jump: bool,
+    vblank: bool,
+    logic: bool,
+) -> Quirks {
+    Quirks {
+        shift,
+        memory_increment_by_x,
+        memory_leave_i_unchanged,
+        wrap,
+        jump,
+        vblank,
+        logic,
+    }
+}
+
+pub const PLATFORMS: &[Platform] = &[
+    Platform {
+        id: "originalChip8",
+        name: "CHIP-8 (COSMAC VIP)",
+        quirks: quirks(false, false, false, false, false, true, true),
+        tickrate: 15,
+        generator: Generator::Vip,
+    },
+    Platform {
+        id: "hybridVIP",
+        name: "CHIP-8 with hybrid VIP instructions",
+        quirks: quirks(false, false, false, false, false, true, true),
+        tickrate: 15,
+        generator: Generator::Vip,
+    },
+    Platform {
+        id: "modernChip8",
+        name: "Modern CHIP-8",
+        quirks: quirks(false, false, false, false, false, false, false),
+        tickrate: 12,
+        generator: Generator::Xorshift,
+    },
+    Platform {
+        id: "chip8x",
+        name: "CHIP-8X",
+        quirks: quirks(false, false, false, false, false, true, true),
+        tickrate: 15,
+        generator: Generator::Vip,
+    },
+    Platform {
+        id: "chip48",
+        name: "CHIP-48",
+        quirks: quirks(true, true, false, false, true, false, false),
+        tickrate: 30,
+        generator: Generator::Xorshift,
+    },
+    Platform {
+        id: "superchip1",
+        name: "SUPER-CHIP 1.0",
+        quirks: quirks(true, true, false, false, true, false, false),
+        tickrate: 30,
+        generator: Generator::Xorshift,
+    },
+    Platform {
+        id: "superchip",
+        name: "SUPER-CHIP 1.1",
+        quirks: quirks(true, false, true, false, true, false, false),
+        tickrate: 30,
+        generator: Generator::Xorshift,
+    },
+    Platform {
+        id: "megachip8",
+        name: "MEGA-CHIP",
+        quirks: quirks(true, false, true, false, true, false, false),
+        tickrate: 1000,
+        generator: Generator::Xorshift,
+    },
+    Platform {
+        id: "xochip",
+        name: "XO-CHIP",
+        quirks: quirks(false, false, false, true, false, false, false),
+        tickrate: 100,
+        generator: Generator::Xorshift,
+    },
+];
+
+impl Platform {
+    pub fn find(id: &str) -> Option<&'static Platform> {
+        PLATFORMS.iter().find(|p| p.id == id)
+    }
+}
+
+impl Quirks {
+    /// The chip-8-database names of the quirks.
+    pub const NAMES: [&'static str; 7] = [
+        "shift",
+        "memoryIncrementByX",
+        "memoryLeaveIUnchanged",
+        "wrap",
+        "jump",
+        "vblank",
+        "logic",
+    ];
+
+    /// Reads a quirk by its chip-8-database name.
+    pub fn get(mut self, name: &str) -> Result<bool, String> {
+        self.quirk(name).map(|quirk| *quirk)
+    }
+    /// Sets a quirk by its chip-8-database name.
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        *self.quirk(name)? = value;
+        Ok(())
+    }
+    fn quirk(&mut self, name: &str) -> Result<&mut bool, String> {
+        Ok(match name {
+            "shift" => &mut self.shift,
+            "memoryIncrementByX" => &mut self.memory_increment_by_x,
+            "memoryLeaveIUnchanged" => &mut self.memory_leave_i_unchanged,
+            "wrap" => &mut self.wrap,
+            "jump" => &mut self.jump,
+            "vblank" => &mut self.vblank,
+            "logic" => &mut self.logic,
+            _ => return Err(format!("Unknown quirk {}", name)),
+        })
+    }
+}
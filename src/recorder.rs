@@ -0,0 +1,362 @@
+use crate::audio::{AudioBackend, BeepConfig, SoundUpdate, WavSink};
+use crate::display::{Display, DISPLAY_SIZE};
+use crate::screenshot::{self, Palette};
+use crate::WDW_HEIGHT;
+use crate::WDW_WIDTH;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Frames are captured once per 60 Hz timer tick.
+const FRAME_RATE: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordingFormat {
+    /// A looping animated GIF of the screen.
+    Gif,
+    /// Packed 1-bit frames (`-f rawvideo -pix_fmt monob -s 64x32 -r 60` in
+    /// ffmpeg terms) with the beeper written next to it as a WAV.
+    Raw,
+}
+
+/// Captures every frame of a session and writes it out when finished.
+pub struct Recorder {
+    path: PathBuf,
+    format: RecordingFormat,
+    scale: u32,
+    /// Colours for GIF recordings.
+    pub palette: Palette,
+    frames: Vec<[bool; DISPLAY_SIZE]>,
+    /// The beeper, rendered alongside raw recordings.
+    beep_track: Option<WavSink>,
+}
+
+impl Recorder {
+    /// Records to `path`, as a GIF if it ends in `.gif` and as a raw dump
+    /// otherwise.
+    pub fn new(path: &Path, scale: u32, beep: BeepConfig) -> Self {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("gif") => RecordingFormat::Gif,
+            _ => RecordingFormat::Raw,
+        };
+        let beep_track = match format {
+            RecordingFormat::Gif => None,
+            RecordingFormat::Raw => Some(WavSink::new(&path.with_extension("wav"), beep)),
+        };
+        Self {
+            path: path.to_path_buf(),
+            format,
+            scale: scale.max(1),
+            palette: Palette::default(),
+            frames: Vec::new(),
+            beep_track,
+        }
+    }
+    pub fn format(&self) -> RecordingFormat {
+        self.format
+    }
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn capture(&mut self, display: &Display, sound: &SoundUpdate) {
+        self.frames.push(*display.pixels());
+        if let Some(beep_track) = &mut self.beep_track {
+            beep_track.apply(sound);
+        }
+    }
+    pub fn finish(mut self) -> Result<(), String> {
+        match self.format {
+            RecordingFormat::Gif => {
+                let gif = encode_gif(&self.frames, self.palette, self.scale);
+                write(&self.path, &gif)?;
+            }
+            RecordingFormat::Raw => {
+                let mut raw = Vec::new();
+                for frame in &self.frames {
+                    raw.extend_from_slice(&screenshot::pack_pixels(frame));
+                }
+                write(&self.path, &raw)?;
+                if let Some(beep_track) = &mut self.beep_track {
+                    beep_track.finish()?;
+                }
+            }
+        }
+        println!(
+            "Saved {} frames to {}",
+            self.frames.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), String> {
+    fs::write(path, data).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Encodes the frames as a looping GIF89a at 60 frames per second.
+pub fn encode_gif(frames: &[[bool; DISPLAY_SIZE]], palette: Palette, scale: u32) -> Vec<u8> {
+    let width = (WDW_WIDTH * scale) as u16;
+    let height = (WDW_HEIGHT * scale) as u16;
+    let mut out = b"GIF89a".to_vec();
+    out.extend_from_slice(&width.to_le_bytes());
+    out.extend_from_slice(&height.to_le_bytes());
+    // Two entry global colour table, background colour 0, square pixels
+    out.extend_from_slice(&[0x80, 0, 0]);
+    out.extend_from_slice(&palette.off);
+    out.extend_from_slice(&palette.on);
+    // Loop forever
+    out.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+    out.extend_from_slice(b"NETSCAPE2.0");
+    out.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+    for (n, frame) in frames.iter().enumerate() {
+        // GIF delays are in hundredths of a second, so spread the rounding
+        // over the frames to keep the average at 60 per second.
+        let n = n as u32;
+        let delay = ((n + 1) * 100 / FRAME_RATE - n * 100 / FRAME_RATE) as u16;
+        out.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[0x00, 0x00]);
+
+        out.push(0x2C);
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&width.to_le_bytes());
+        out.extend_from_slice(&height.to_le_bytes());
+        out.push(0);
+
+        let mut indices = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height as u32 {
+            for x in 0..width as u32 {
+                let i = (WDW_WIDTH * (y / scale) + (x / scale)) as usize;
+                indices.push(frame[i] as u8);
+            }
+        }
+        out.push(GIF_MIN_CODE_SIZE);
+        for block in lzw_encode(&indices).chunks(255) {
+            out.push(block.len() as u8);
+            out.extend_from_slice(block);
+        }
+        out.push(0);
+    }
+    out.push(0x3B);
+    out
+}
+
+const GIF_MIN_CODE_SIZE: u8 = 2;
+
+/// Variable-width LZW as used by GIF image data, packed least significant
+/// bit first.
+fn lzw_encode(indices: &[u8]) -> Vec<u8> {
+    let clear_code = 1u16 << GIF_MIN_CODE_SIZE;
+    let end_code = clear_code + 1;
+
+    let mut out = Vec::new();
+    let mut bit_buffer = 0u32;
+    let mut bit_count = 0u32;
+    let mut emit = |code: u16, size: u32, out: &mut Vec<u8>| {
+        bit_buffer |= (code as u32) << bit_count;
+        bit_count += size;
+        while bit_count >= 8 {
+            out.push(bit_buffer as u8);
+            bit_buffer >>= 8;
+            bit_count -= 8;
+        }
+    };
+
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = GIF_MIN_CODE_SIZE as u32 + 1;
+    emit(clear_code, code_size, &mut out);
+
+    let mut pixels = indices.iter();
+    let mut current = match pixels.next() {
+        Some(&first) => first as u16,
+        None => {
+            emit(end_code, code_size, &mut out);
+            emit(0, 7, &mut out);
+            return out;
+        }
+    };
+    for &pixel in pixels {
+        if let Some(&code) = table.get(&(current, pixel)) {
+            current = code;
+            continue;
+        }
+        emit(current, code_size, &mut out);
+        if next_code < 4095 {
+            table.insert((current, pixel), next_code);
+            next_code += 1;
+            if next_code > (1 << code_size) {
+                code_size += 1;
+            }
+        } else {
+            emit(clear_code, code_size, &mut out);
+            table.clear();
+            next_code = end_code + 1;
+            code_size = GIF_MIN_CODE_SIZE as u32 + 1;
+        }
+        current = pixel as u16;
+    }
+    emit(current, code_size, &mut out);
+    emit(end_code, code_size, &mut out);
+    // Flush whatever is left in the bit buffer
+    emit(0, 7, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SoundEvent;
+
+    /// Reads GIF image data back into indices.
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let clear_code = 1u16 << GIF_MIN_CODE_SIZE;
+        let end_code = clear_code + 1;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = GIF_MIN_CODE_SIZE as u32 + 1;
+        let (mut bit_buffer, mut bit_count, mut pos) = (0u32, 0u32, 0);
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            while bit_count < code_size {
+                bit_buffer |= (data[pos] as u32) << bit_count;
+                pos += 1;
+                bit_count += 8;
+            }
+            let code = (bit_buffer & ((1 << code_size) - 1)) as u16;
+            bit_buffer >>= code_size;
+            bit_count -= code_size;
+            if code == clear_code {
+                table = (0..clear_code).map(|c| vec![c as u8]).collect();
+                table.extend([Vec::new(), Vec::new()]);
+                code_size = GIF_MIN_CODE_SIZE as u32 + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return out;
+            }
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) => entry.clone(),
+                // A code the encoder made from the last string and its own
+                // first index
+                (None, Some(previous)) => [previous.as_slice(), &previous[..1]].concat(),
+                (None, None) => panic!("code {} before any string", code),
+            };
+            if let Some(mut previous) = previous.take() {
+                previous.push(entry[0]);
+                table.push(previous);
+                if table.len() == 1 << code_size && code_size < 12 {
+                    code_size += 1;
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_gif() {
+        assert_eq!(lzw_decode(&lzw_encode(&[])), []);
+        assert_eq!(lzw_decode(&lzw_encode(&[1])), [1]);
+        // Long and noisy enough to fill the table, so it has to be cleared
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                (x & 1) as u8
+            })
+            .collect();
+        assert_eq!(lzw_decode(&lzw_encode(&noise)), noise);
+
+        let mut frames = [[false; DISPLAY_SIZE]; 2];
+        frames[1][WDW_WIDTH as usize + 3] = true;
+        let palette = Palette::default();
+        let gif = encode_gif(&frames, palette, 2);
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[128, 0, 64, 0]);
+        assert_eq!(&gif[13..16], &palette.off);
+        assert_eq!(&gif[16..19], &palette.on);
+        let mut pos = 19;
+        let mut images = Vec::new();
+        loop {
+            let sub_blocks = |pos: &mut usize| {
+                let mut data = Vec::new();
+                while gif[*pos] != 0 {
+                    let len = gif[*pos] as usize;
+                    data.extend_from_slice(&gif[*pos + 1..*pos + 1 + len]);
+                    *pos += 1 + len;
+                }
+                *pos += 1;
+                data
+            };
+            match gif[pos] {
+                0x21 => {
+                    pos += 2;
+                    sub_blocks(&mut pos);
+                }
+                0x2C => {
+                    assert_eq!(gif[pos + 10], GIF_MIN_CODE_SIZE);
+                    pos += 11;
+                    images.push(lzw_decode(&sub_blocks(&mut pos)));
+                }
+                0x3B => break,
+                byte => panic!("unexpected block {:#x} at {}", byte, pos),
+            }
+        }
+        assert_eq!(pos, gif.len() - 1);
+        assert_eq!(images.len(), 2);
+        assert!(images[0].iter().all(|&index| index == 0));
+        // The lit pixel, doubled both ways
+        let lit: Vec<usize> = (0..images[1].len())
+            .filter(|&n| images[1][n] == 1)
+            .collect();
+        assert_eq!(lit, [2 * 128 + 6, 2 * 128 + 7, 3 * 128 + 6, 3 * 128 + 7]);
+    }
+
+    #[test]
+    fn test_raw() {
+        let path = std::env::temp_dir().join(format!("chipn80-{}.raw", std::process::id()));
+        let mut recorder = Recorder::new(&path, 1, BeepConfig::default());
+        assert_eq!(recorder.format(), RecordingFormat::Raw);
+        let mut display = Display::new();
+        let silent = SoundUpdate {
+            events: Vec::new(),
+            pattern: None,
+            time: 1.0 / 60.0,
+        };
+        recorder.capture(&display, &silent);
+        display.set_display_at_location(9, 0, true).unwrap();
+        let beep = SoundUpdate {
+            events: vec![SoundEvent {
+                time: 1.0 / 60.0,
+                on: true,
+            }],
+            pattern: None,
+            time: 2.0 / 60.0,
+        };
+        recorder.capture(&display, &beep);
+        recorder.finish().unwrap();
+
+        let raw = fs::read(&path).unwrap();
+        assert_eq!(raw.len(), 2 * DISPLAY_SIZE / 8);
+        assert!(raw[..DISPLAY_SIZE / 8].iter().all(|&byte| byte == 0));
+        assert_eq!(raw[DISPLAY_SIZE / 8 + 1], 0b0100_0000);
+        let wav_path = path.with_extension("wav");
+        let wav = fs::read(&wav_path).unwrap();
+        let _ = (fs::remove_file(&path), fs::remove_file(&wav_path));
+        assert_eq!(&wav[..4], b"RIFF");
+        // Two frames at 44.1 kHz, 16 bits a sample, silent until the beep
+        assert_eq!(wav.len(), 44 + 1470 * 2);
+        let samples: Vec<i16> = wav[44..]
+            .chunks(2)
+            .map(|s| i16::from_le_bytes([s[0], s[1]]))
+            .collect();
+        assert!(samples[..735].iter().all(|&s| s == 0));
+        assert!(samples[735..].iter().any(|&s| s != 0));
+    }
+}
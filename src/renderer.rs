@@ -1,3 +1,7 @@
+use crate::display::Display;
+use crate::phosphor::{Persistence, Phosphor};
+use crate::screenshot::Palette;
+use crate::text::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
 use crate::WDW_HEIGHT;
 use crate::WDW_SIZE_SCALAR;
 use crate::WDW_WIDTH;
@@ -5,42 +9,139 @@ use sdl2::render::WindowCanvas;
 use sdl2::video::Window;
 use sdl2::{pixels::Color, rect::Rect};
 
+/// Window pixels per font pixel on the emulator's own screens.
+const TEXT_SCALE: u32 = 2;
+const TEXT_CELL_WIDTH: u32 = (GLYPH_WIDTH + 1) * TEXT_SCALE;
+const TEXT_CELL_HEIGHT: u32 = (GLYPH_HEIGHT + 1) * TEXT_SCALE;
+
 pub struct Renderer {
     canvas: WindowCanvas,
-    display_backing: [bool; WDW_WIDTH as usize * WDW_HEIGHT as usize],
+    phosphor: Phosphor,
+    palette: Palette,
 }
 
 impl Renderer {
     pub fn new(window: Window) -> Result<Renderer, String> {
         let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        // initialize to all pixels off at first
-        let display_backing = [false; WDW_WIDTH as usize * WDW_HEIGHT as usize];
         Ok(Renderer {
             canvas,
-            display_backing,
+            phosphor: Phosphor::new(Persistence::Off),
+            palette: Palette::default(),
         })
     }
-    pub fn draw(&mut self) -> Result<(), String> {
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+    /// Presents the display, framed in red when `beep_indicator` is set so
+    /// beeps can be seen when they can't be heard.
+    pub fn draw(&mut self, display: &Display, beep_indicator: bool) -> Result<(), String> {
+        let [r, g, b] = self.palette.off;
+        self.canvas.set_draw_color(Color::RGB(r, g, b));
         self.canvas.clear();
-        self.canvas.set_draw_color(Color::RGB(255, 255, 255));
+        let levels = self.phosphor.levels(display.pixels());
         // i = WDW_WIDTH * y + x
         for x in 0usize..WDW_WIDTH as usize {
             for y in 0usize..WDW_HEIGHT as usize {
                 let i = (WDW_WIDTH as usize * y) + x;
-                if self.display_backing[i] {
-                    // print!("1");
+                if levels[i] > 0 {
+                    let [r, g, b] = self.shade(levels[i]);
+                    self.canvas.set_draw_color(Color::RGB(r, g, b));
                     self.draw_spot(x as i32, y as i32)?;
-                } else {
-                    // print!("0");
                 }
             }
-            // print!("\n");
         }
-        // print!("\n");
+        if beep_indicator {
+            self.draw_beep_indicator()?;
+        }
+        self.canvas.present();
+        Ok(())
+    }
+    /// How many lines of text, and characters to a line, fit the window.
+    pub fn text_size(&self) -> (usize, usize) {
+        (
+            (WDW_HEIGHT * WDW_SIZE_SCALAR / TEXT_CELL_HEIGHT) as usize,
+            (WDW_WIDTH * WDW_SIZE_SCALAR / TEXT_CELL_WIDTH) as usize,
+        )
+    }
+    /// Presents a screen of text in place of the display, with flagged lines
+    /// highlighted.
+    pub fn draw_text(&mut self, lines: &[(String, bool)]) -> Result<(), String> {
+        let off = Color::RGB(
+            self.palette.off[0],
+            self.palette.off[1],
+            self.palette.off[2],
+        );
+        let on = Color::RGB(self.palette.on[0], self.palette.on[1], self.palette.on[2]);
+        self.canvas.set_draw_color(off);
+        self.canvas.clear();
+        for (row, (line, highlighted)) in lines.iter().enumerate() {
+            let top = (row as u32 * TEXT_CELL_HEIGHT) as i32;
+            if *highlighted {
+                self.canvas.set_draw_color(on);
+                self.canvas.fill_rect(Rect::new(
+                    0,
+                    top,
+                    WDW_WIDTH * WDW_SIZE_SCALAR,
+                    TEXT_CELL_HEIGHT,
+                ))?;
+            }
+            self.canvas
+                .set_draw_color(if *highlighted { off } else { on });
+            let mut spots = Vec::new();
+            for (column, c) in line.chars().enumerate() {
+                let left = (column as u32 * TEXT_CELL_WIDTH + TEXT_SCALE) as i32;
+                for (y, bits) in text::glyph(c).iter().enumerate() {
+                    for x in 0..GLYPH_WIDTH {
+                        if bits & (0b100 >> x) != 0 {
+                            spots.push(Rect::new(
+                                left + (x * TEXT_SCALE) as i32,
+                                top + TEXT_SCALE as i32 + (y as u32 * TEXT_SCALE) as i32,
+                                TEXT_SCALE,
+                                TEXT_SCALE,
+                            ));
+                        }
+                    }
+                }
+            }
+            self.canvas.fill_rects(&spots)?;
+        }
         self.canvas.present();
         Ok(())
     }
+    fn draw_beep_indicator(&mut self) -> Result<(), String> {
+        let width = WDW_WIDTH * WDW_SIZE_SCALAR;
+        let height = WDW_HEIGHT * WDW_SIZE_SCALAR;
+        let thickness = (WDW_SIZE_SCALAR / 2).max(1);
+        self.canvas.set_draw_color(Color::RGB(255, 0, 0));
+        self.canvas.fill_rects(&[
+            Rect::new(0, 0, width, thickness),
+            Rect::new(0, (height - thickness) as i32, width, thickness),
+            Rect::new(0, 0, thickness, height),
+            Rect::new((width - thickness) as i32, 0, thickness, height),
+        ])
+    }
+    /// The colour of a pixel at `level`, between the palette's unlit and
+    /// lit colours.
+    fn shade(&self, level: u8) -> [u8; 3] {
+        let mut colour = [0u8; 3];
+        for (c, (&off, &on)) in colour
+            .iter_mut()
+            .zip(self.palette.off.iter().zip(&self.palette.on))
+        {
+            *c = (off as i32 + (on as i32 - off as i32) * level as i32 / 255) as u8;
+        }
+        colour
+    }
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+    pub fn persistence(&self) -> Persistence {
+        self.phosphor.mode()
+    }
+    /// Lets the phosphor glow fade by a frame, once per 60 Hz tick.
+    pub fn advance_phosphor(&mut self, display: &Display) {
+        self.phosphor.advance(display.pixels());
+    }
+    pub fn set_persistence(&mut self, mode: Persistence) {
+        self.phosphor = Phosphor::new(mode);
+    }
     pub fn draw_spot(&mut self, x: i32, y: i32) -> Result<(), String> {
         self.canvas.fill_rect(Rect::new(
             x * WDW_SIZE_SCALAR as i32,
@@ -50,42 +151,4 @@ impl Renderer {
         ))?;
         Ok(())
     }
-    pub fn set_display_at_location(
-        &mut self,
-        x: usize,
-        y: usize,
-        value: bool,
-    ) -> Result<(), String> {
-        self.display_backing[WDW_WIDTH as usize * y + x] = value;
-        Ok(())
-    }
-    pub fn print_debug(&mut self) {
-        println!("{:?}", self.display_backing);
-        for y in 0..WDW_HEIGHT as usize {
-            for x in 0..WDW_WIDTH as usize {
-                print!(
-                    "{}",
-                    if self.display_backing[WDW_WIDTH as usize * y + x] {
-                        1
-                    } else {
-                        0
-                    }
-                );
-            }
-            println!();
-        }
-    }
-    pub fn get_display_at_location(&self, x: usize, y: usize) -> Result<bool, String> {
-        Ok(self.display_backing
-            [(WDW_WIDTH as usize * y + x) % (WDW_WIDTH as usize * WDW_HEIGHT as usize)])
-    }
-    pub fn replace_display(
-        &mut self,
-        replacement: [bool; WDW_WIDTH as usize * WDW_HEIGHT as usize],
-    ) {
-        self.display_backing = replacement;
-    }
-    pub fn clear_display(&mut self) {
-        self.replace_display([false; WDW_WIDTH as usize * WDW_HEIGHT as usize])
-    }
 }
@@ -1,29 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
 use std::time::SystemTime;
+
+/// The seed for runs that must repeat exactly, such as tests and fuzzing.
+pub const FIXED_SEED: u32 = 4;
+
+/// How CXNN comes up with its random numbers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Generator {
+    /// The low byte of a 32-bit xorshift, this emulator's own.  A zero
+    /// seed, which xorshift never leaves, is taken as 1.
+    #[default]
+    Xorshift,
+    /// The COSMAC VIP interpreter's: a 16-bit seed is stepped, its low
+    /// byte picks a byte from the interpreter's second page, and that is
+    /// added into its high byte and the sum folded back on itself.  Needs
+    /// the interpreter loaded with `set_vip_interpreter`, and is xorshift
+    /// until it is.
+    Vip,
+    /// The example `rand()` from the C standard, as interpreters written
+    /// in C over a simple runtime get it: a 32-bit linear congruential
+    /// generator whose bits 16 to 23 make the byte.
+    Rand,
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xorshift" => Ok(Generator::Xorshift),
+            "vip" => Ok(Generator::Vip),
+            "rand" => Ok(Generator::Rand),
+            _ => Err(format!(
+                "Unknown generator {}, try xorshift, vip or rand",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Generator::Xorshift => "xorshift",
+            Generator::Vip => "vip",
+            Generator::Rand => "rand",
+        })
+    }
+}
+
+impl Generator {
+    /// Whether it can run: the VIP's needs the interpreter loaded.
+    pub fn available(self) -> bool {
+        self != Generator::Vip || VIP_PAGE.get().is_some()
+    }
+}
+
+/// Bytes 0x100 to 0x1FF of the VIP's CHIP-8 interpreter, the page its CXNN
+/// routine indexes, once loaded.
+static VIP_PAGE: OnceLock<[u8; 256]> = OnceLock::new();
+
+/// Loads the VIP's CHIP-8 interpreter, the 512 bytes it occupies from
+/// address 0, for `Generator::Vip`.  Only the first one loaded is kept.
+pub fn set_vip_interpreter(image: &[u8]) -> Result<(), String> {
+    if image.len() != 0x200 {
+        return Err(format!(
+            "The VIP interpreter is 512 bytes, not {}",
+            image.len()
+        ));
+    }
+    let mut page = [0u8; 256];
+    page.copy_from_slice(&image[0x100..]);
+    let _ = VIP_PAGE.set(page);
+    Ok(())
+}
+
+/// Steps the VIP's CXNN routine, with the seed held in R9 as `state`:
+///
+/// ```text
+/// INC 9; GLO 9; PLO E; GHI 3; PHI E   RE points into the page at R9.0
+/// GHI 9; SEX E; ADD; STR 6            VX = R9.1 + page[R9.0]
+/// SHRC; SEX 6; ADD; PHI 9; STR 6      VX = R9.1 = VX + (VX >> 1, carry in)
+/// LDA 5; AND; STR 6                   VX &= NN, done by the caller
+/// ```
+fn vip_next(state: &mut u16, page: &[u8; 256]) -> u8 {
+    let [high, low] = state.wrapping_add(1).to_be_bytes();
+    let (sum, carry) = high.overflowing_add(page[low as usize]);
+    let high = sum.wrapping_add(sum >> 1 | (carry as u8) << 7);
+    *state = u16::from_be_bytes([high, low]);
+    high
+}
+
 pub(crate) struct RandomNumberGenerator {
+    pub(crate) generator: Generator,
+    /// Where `state` started, kept so the sequence can be started over.
+    pub(crate) seed: u32,
     pub(crate) state: u32,
 }
 
 impl RandomNumberGenerator {
-    pub fn new(state: u32) -> Self {
-        Self { state }
+    pub fn new(generator: Generator, seed: u32) -> Self {
+        let generator = match generator.available() {
+            true => generator,
+            false => Generator::Xorshift,
+        };
+        let state = match generator {
+            Generator::Xorshift => seed.max(1),
+            Generator::Vip => seed & 0xFFFF,
+            Generator::Rand => seed,
+        };
+        Self {
+            generator,
+            seed,
+            state,
+        }
     }
     pub fn seed_with_time(&mut self) {
-        let time_since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
-        self.state = match time_since_epoch {
-            Ok(time) => time.as_millis() as u32,
-            Err(_) => {
-                println!("System time is before UNIX Epoch!  RNG Seed is static.");
-                4u32
-            }
-        };
+        *self = Self::new(self.generator, time_seed());
     }
     pub fn next(&mut self) -> u8 {
-        let mut x = self.state;
-        x ^= x << 13;
-        x ^= x >> 17;
-        x ^= x << 5;
-        self.state = x;
-        x as u8
+        match self.generator {
+            Generator::Xorshift => {
+                let mut x = self.state;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.state = x;
+                x as u8
+            }
+            Generator::Vip => {
+                let mut state = self.state as u16;
+                let byte = vip_next(&mut state, VIP_PAGE.get().unwrap());
+                self.state = state as u32;
+                byte
+            }
+            Generator::Rand => {
+                self.state = self.state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (self.state >> 16) as u8
+            }
+        }
+    }
+}
+
+/// A seed from the wall clock.
+pub fn time_seed() -> u32 {
+    let time_since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    match time_since_epoch {
+        Ok(time) => time.as_millis() as u32,
+        Err(_) => {
+            println!("System time is before UNIX Epoch!  RNG Seed is static.");
+            FIXED_SEED
+        }
     }
 }
 
@@ -32,9 +161,39 @@ mod tests {
     use super::*;
     #[test]
     fn test_rng() {
-        let mut rng = RandomNumberGenerator::new(0b11110111101000011100110011100001);
+        let mut rng =
+            RandomNumberGenerator::new(Generator::Xorshift, 0b11110111101000011100110011100001);
         for _ in 0..255 {
             println!("{}", rng.next());
         }
+
+        let mut rand = RandomNumberGenerator::new(Generator::Rand, 1);
+        // rand() % 256 for the C standard's example, seeded with 1
+        let first: Vec<u8> = (0..3).map(|_| rand.next()).collect();
+        assert_eq!(
+            first,
+            [(16838 % 256) as u8, (5758 % 256) as u8, (10113 % 256) as u8]
+        );
+
+        // Worked through the VIP routine's instructions by hand, with a page
+        // where each byte holds its own offset.  The first sum carries, and
+        // the carry comes back in at the top of the shifted byte.
+        let page: [u8; 256] = std::array::from_fn(|n| n as u8);
+        let mut state = 0x80FE;
+        let first: Vec<u8> = (0..3).map(|_| vip_next(&mut state, &page)).collect();
+        assert_eq!(first, [0x3E, 0x5E, 0x8E]);
+        assert_eq!(state, 0x8E01);
+        assert!(set_vip_interpreter(&[0; 256]).is_err());
+        assert_eq!("VIP".parse(), Ok(Generator::Vip));
+
+        for generator in [Generator::Xorshift, Generator::Rand] {
+            assert_eq!(generator.to_string().parse(), Ok(generator));
+            let run = |seed| {
+                let mut rng = RandomNumberGenerator::new(generator, seed);
+                (0..16).map(|_| rng.next()).collect::<Vec<_>>()
+            };
+            assert_eq!(run(FIXED_SEED), run(FIXED_SEED));
+            assert_ne!(run(FIXED_SEED), run(FIXED_SEED + 1));
+        }
     }
 }
@@ -0,0 +1,522 @@
+use crate::asm;
+use crate::json::Json;
+use crate::machine::ChipMachine;
+use crate::quirks::{Platform, Quirks};
+use crate::rng::{self, Generator};
+use crate::screenshot::Palette;
+use crate::sha1::sha1_hex;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the database and per-ROM settings live: `$XDG_CONFIG_HOME/chipn80`,
+/// or `~/.config/chipn80`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("chipn80"))
+}
+
+/// Controller buttons the database can bind to keypad keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+}
+
+impl Button {
+    fn from_name(name: &str) -> Option<Button> {
+        match name {
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "left" => Some(Button::Left),
+            "right" => Some(Button::Right),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            _ => None,
+        }
+    }
+}
+
+/// Which keypad key each button presses for the running ROM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Keymap {
+    bindings: Vec<(Button, usize)>,
+}
+
+impl Keymap {
+    pub fn key(&self, button: Button) -> Option<usize> {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|(b, _)| *b == button)
+            .map(|&(_, key)| key)
+    }
+}
+
+/// One layer of settings for a ROM, in the shape of a chip-8-database ROM
+/// entry.  The database, the user's settings file and the command line each
+/// supply a layer, and later layers win.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomSettings {
+    /// Platforms the ROM runs on, preferred first.
+    pub platforms: Vec<String>,
+    /// Quirks the ROM needs on a particular platform.
+    pub quirky_platforms: Vec<(String, Vec<(String, bool)>)>,
+    /// Quirks whatever the platform.
+    pub quirks: Vec<(String, bool)>,
+    pub tickrate: Option<u32>,
+    /// How CXNN makes random numbers, when not the platform's way.
+    pub rng: Option<Generator>,
+    /// A fixed seed for CXNN, for runs that repeat exactly.
+    pub seed: Option<u32>,
+    pub palette: Option<Palette>,
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomSettings {
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let mut settings = RomSettings::default();
+        for platform in json.get("platforms").map(Json::items).unwrap_or(&[]) {
+            let id = platform.as_str().ok_or("Platforms must be strings")?;
+            settings.platforms.push(id.to_string());
+        }
+        if let Some(quirky) = json.get("quirkyPlatforms") {
+            for (platform, quirks) in quirky.members() {
+                settings
+                    .quirky_platforms
+                    .push((platform.clone(), quirk_list(quirks)?));
+            }
+        }
+        if let Some(quirks) = json.get("quirks") {
+            settings.quirks = quirk_list(quirks)?;
+        }
+        if let Some(tickrate) = json.get("tickrate") {
+            let tickrate = tickrate.as_f64().ok_or("The tickrate must be a number")?;
+            settings.tickrate = Some(tickrate as u32);
+        }
+        if let Some(rng) = json.get("rng") {
+            settings.rng = Some(rng.as_str().ok_or("The rng must be a string")?.parse()?);
+        }
+        if let Some(seed) = json.get("seed") {
+            let seed = seed.as_f64().ok_or("The seed must be a number")?;
+            settings.seed = Some(seed as u32);
+        }
+        if let Some(pixels) = json.get("colors").and_then(|c| c.get("pixels")) {
+            let colours = pixels
+                .items()
+                .iter()
+                .map(|c| c.as_str().and_then(parse_colour))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("Colours must be written #rrggbb")?;
+            if let [off, on, ..] = colours[..] {
+                settings.palette = Some(Palette { off, on });
+            }
+        }
+        if let Some(keys) = json.get("keys") {
+            for (button, key) in keys.members() {
+                let key = key.as_f64().ok_or("Keys must be keypad numbers")?;
+                settings.keys.push((button.clone(), key as u8));
+            }
+        }
+        Ok(settings)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut members = Vec::new();
+        let strings =
+            |list: &[String]| Json::Array(list.iter().cloned().map(Json::String).collect());
+        let quirks = |list: &[(String, bool)]| {
+            Json::Object(
+                list.iter()
+                    .map(|(name, value)| (name.clone(), Json::Bool(*value)))
+                    .collect(),
+            )
+        };
+        if !self.platforms.is_empty() {
+            members.push(("platforms".to_string(), strings(&self.platforms)));
+        }
+        if !self.quirky_platforms.is_empty() {
+            let quirky = self
+                .quirky_platforms
+                .iter()
+                .map(|(platform, list)| (platform.clone(), quirks(list)))
+                .collect();
+            members.push(("quirkyPlatforms".to_string(), Json::Object(quirky)));
+        }
+        if !self.quirks.is_empty() {
+            members.push(("quirks".to_string(), quirks(&self.quirks)));
+        }
+        if let Some(tickrate) = self.tickrate {
+            members.push(("tickrate".to_string(), Json::Number(tickrate as f64)));
+        }
+        if let Some(rng) = self.rng {
+            members.push(("rng".to_string(), Json::String(rng.to_string())));
+        }
+        if let Some(seed) = self.seed {
+            members.push(("seed".to_string(), Json::Number(seed as f64)));
+        }
+        if let Some(palette) = self.palette {
+            let pixels = [palette.off, palette.on]
+                .iter()
+                .map(|[r, g, b]| Json::String(format!("#{:02x}{:02x}{:02x}", r, g, b)))
+                .collect();
+            members.push((
+                "colors".to_string(),
+                Json::Object(vec![("pixels".to_string(), Json::Array(pixels))]),
+            ));
+        }
+        if !self.keys.is_empty() {
+            let keys = self
+                .keys
+                .iter()
+                .map(|(button, key)| (button.clone(), Json::Number(*key as f64)))
+                .collect();
+            members.push(("keys".to_string(), Json::Object(keys)));
+        }
+        Json::Object(members)
+    }
+
+    /// Lays `other` over these settings.
+    pub fn merge(&mut self, other: &RomSettings) {
+        if !other.platforms.is_empty() {
+            self.platforms = other.platforms.clone();
+        }
+        self.quirky_platforms
+            .extend(other.quirky_platforms.iter().cloned());
+        self.quirks.extend(other.quirks.iter().cloned());
+        self.tickrate = other.tickrate.or(self.tickrate);
+        self.rng = other.rng.or(self.rng);
+        self.seed = other.seed.or(self.seed);
+        self.palette = other.palette.or(self.palette);
+        self.keys.extend(other.keys.iter().cloned());
+    }
+}
+
+fn quirk_list(json: &Json) -> Result<Vec<(String, bool)>, String> {
+    json.members()
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .as_bool()
+                .ok_or_else(|| format!("Quirk {} must be true or false", name))?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+/// Reads `#rrggbb`.
+pub fn parse_colour(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Everything picked for the running ROM once all the layers are applied.
+#[derive(Clone, Debug)]
+pub struct RomConfig {
+    pub sha1: String,
+    pub title: Option<String>,
+    pub platform: Option<&'static Platform>,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub generator: Generator,
+    /// Seeded from the clock when not given.
+    pub seed: Option<u32>,
+    pub palette: Palette,
+    pub keymap: Keymap,
+}
+
+impl RomConfig {
+    pub fn resolve(sha1: &str, title: Option<String>, layers: &[&RomSettings]) -> Self {
+        let platform_id = layers.iter().rev().find_map(|l| l.platforms.first());
+        let platform = platform_id.and_then(|id| {
+            let platform = Platform::find(id);
+            if platform.is_none() {
+                println!("Unknown platform {}, ignoring it", id);
+            }
+            platform
+        });
+
+        let mut quirks = platform.map(|p| p.quirks).unwrap_or_default();
+        for layer in layers {
+            let quirky = layer
+                .quirky_platforms
+                .iter()
+                .filter(|(id, _)| Some(id) == platform_id)
+                .flat_map(|(_, list)| list);
+            for (name, value) in quirky.chain(&layer.quirks) {
+                // Quirks this emulator doesn't know about are left out
+                let _ = quirks.set(name, *value);
+            }
+        }
+
+        let mut keymap = Keymap::default();
+        for (name, key) in layers.iter().flat_map(|l| &l.keys) {
+            if let Some(button) = Button::from_name(name) {
+                keymap.bindings.push((button, (*key & 0xF) as usize));
+            }
+        }
+
+        Self {
+            sha1: sha1.to_string(),
+            title,
+            platform,
+            quirks,
+            cycles_per_frame: layers
+                .iter()
+                .rev()
+                .find_map(|l| l.tickrate)
+                .or(platform.map(|p| p.tickrate))
+                .unwrap_or(ChipMachine::DEFAULT_CYCLES_PER_FRAME)
+                .max(1),
+            generator: layers
+                .iter()
+                .rev()
+                .find_map(|l| l.rng)
+                .or(platform.map(|p| p.generator))
+                .unwrap_or_default(),
+            seed: layers.iter().rev().find_map(|l| l.seed),
+            palette: layers
+                .iter()
+                .rev()
+                .find_map(|l| l.palette)
+                .unwrap_or_default(),
+            keymap,
+        }
+    }
+}
+
+/// A local copy of the community chip-8-database: the `sha1-hashes.json`
+/// and `programs.json` files from its `database` directory.
+#[derive(Default)]
+pub struct RomDatabase {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Json>,
+}
+
+impl RomDatabase {
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let read = |name: &str| -> Result<Json, String> {
+            let path = dir.join(name);
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            Json::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        let mut hashes = HashMap::new();
+        for (hash, index) in read("sha1-hashes.json")?.members() {
+            if let Some(index) = index.as_f64() {
+                hashes.insert(hash.to_ascii_lowercase(), index as usize);
+            }
+        }
+        let programs = match read("programs.json")? {
+            Json::Array(programs) => programs,
+            _ => return Err("programs.json should hold a list of programs".to_string()),
+        };
+        Ok(Self { hashes, programs })
+    }
+
+    /// The program's title and its settings for this particular ROM.
+    pub fn lookup(&self, sha1: &str) -> Result<Option<(Option<String>, RomSettings)>, String> {
+        let Some(program) = self.hashes.get(sha1).and_then(|&i| self.programs.get(i)) else {
+            return Ok(None);
+        };
+        let title = program
+            .get("title")
+            .and_then(Json::as_str)
+            .map(String::from);
+        let settings = match program.get("roms").and_then(|roms| roms.get(sha1)) {
+            Some(rom) => RomSettings::from_json(rom)?,
+            None => RomSettings::default(),
+        };
+        Ok(Some((title, settings)))
+    }
+}
+
+/// Finds the settings for each ROM that is loaded, from the database, the
+/// user's per-ROM settings files and the command line.
+pub struct RomConfigStore {
+    database: RomDatabase,
+    /// Per-ROM settings files, named `<sha1>.json`.
+    overrides_dir: Option<PathBuf>,
+    command_line: RomSettings,
+}
+
+impl RomConfigStore {
+    /// Loads the database from `database`, or from the config directory when
+    /// not given.  A missing database just means nothing is recognised.  The
+    /// VIP interpreter for the vip generator is found the same way.
+    pub fn open(
+        database: Option<&Path>,
+        vip_interpreter: Option<&Path>,
+        command_line: RomSettings,
+    ) -> Self {
+        let dir = config_dir();
+        let vip_interpreter = vip_interpreter.map(Path::to_path_buf).or_else(|| {
+            dir.as_ref()
+                .map(|dir| dir.join("vip-interpreter.bin"))
+                .filter(|path| path.is_file())
+        });
+        if let Some(path) = vip_interpreter {
+            let loaded = fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|image| rng::set_vip_interpreter(&image));
+            if let Err(e) = loaded {
+                println!("Unable to load {}: {}", path.display(), e);
+            }
+        }
+        let database = match database {
+            Some(path) => RomDatabase::load(path).unwrap_or_else(|e| {
+                println!("{}", e);
+                RomDatabase::default()
+            }),
+            None => dir
+                .as_ref()
+                .map(|dir| dir.join("chip-8-database"))
+                .filter(|path| path.is_dir())
+                .and_then(|path| RomDatabase::load(&path).map_err(|e| println!("{}", e)).ok())
+                .unwrap_or_default(),
+        };
+        Self {
+            database,
+            overrides_dir: dir.map(|dir| dir.join("roms")),
+            command_line,
+        }
+    }
+
+    fn overrides_path(&self, sha1: &str) -> Option<PathBuf> {
+        self.overrides_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", sha1)))
+    }
+
+    fn read_overrides(&self, sha1: &str) -> Result<RomSettings, String> {
+        let Some(path) = self.overrides_path(sha1).filter(|p| p.exists()) else {
+            return Ok(RomSettings::default());
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Json::parse(&text)
+            .and_then(|json| RomSettings::from_json(&json))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn config_for(&self, sha1: &str) -> Result<RomConfig, String> {
+        let (title, database) = self.database.lookup(sha1)?.unwrap_or_default();
+        let overrides = self.read_overrides(sha1)?;
+        Ok(RomConfig::resolve(
+            sha1,
+            title,
+            &[&database, &overrides, &self.command_line],
+        ))
+    }
+
+    pub fn config_for_rom(&self, rom: &[u8]) -> Result<RomConfig, String> {
+        self.config_for(&sha1_hex(rom))
+    }
+
+    /// Loads a ROM into the machine and sets the machine up the way the ROM
+    /// wants it.
+    pub fn load_rom(&self, machine: &mut ChipMachine, path: &str) -> Result<RomConfig, String> {
+        machine.load_rom(path)?;
+        let config = self.config_for(machine.rom_sha1())?;
+        if let Some(title) = &config.title {
+            println!("Recognised {}", title);
+        }
+        println!(
+            "Running as {} at {} instructions per frame",
+            config.platform.map(|p| p.name).unwrap_or("CHIP-8"),
+            config.cycles_per_frame
+        );
+        machine.quirks = config.quirks;
+        machine.cycles_per_frame = config.cycles_per_frame;
+        machine.set_generator(config.generator);
+        machine.seed_rng(config.seed.unwrap_or_else(rng::time_seed));
+        if !config.generator.available() {
+            println!("No VIP interpreter for the vip generator; see --vip-interpreter");
+        }
+        println!(
+            "Random numbers from {} with seed {}",
+            machine.generator(),
+            machine.rng_seed()
+        );
+        Ok(config)
+    }
+
+    /// Keeps the settings given on the command line for the ROM at `path`,
+    /// so they are picked up without the flags next time.
+    pub fn save_command_line(&self, path: &str) -> Result<PathBuf, String> {
+        let (rom, _) = asm::read_rom(Path::new(path))?;
+        let sha1 = sha1_hex(&rom);
+        let file = self
+            .overrides_path(&sha1)
+            .ok_or("No config directory to save settings in")?;
+        let mut settings = self.read_overrides(&sha1)?;
+        settings.merge(&self.command_line);
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        }
+        fs::write(&file, settings.to_json().to_pretty_string())
+            .map_err(|e| format!("Unable to write {}: {}", file.display(), e))?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_layers() {
+        let database = RomSettings::from_json(
+            &Json::parse(
+                r##"{ "platforms": ["superchip", "xochip"],
+                      "quirkyPlatforms": { "superchip": { "wrap": true }, "xochip": { "logic": true } },
+                      "tickrate": 30,
+                      "colors": { "pixels": ["#101010", "#ff8000"] },
+                      "keys": { "up": 5, "a": 6 } }"##,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let config = RomConfig::resolve("", None, &[&database]);
+        assert_eq!(config.platform.map(|p| p.id), Some("superchip"));
+        assert!(config.quirks.shift & config.quirks.wrap & !config.quirks.logic);
+        assert_eq!(config.cycles_per_frame, 30);
+        assert_eq!(config.generator, Generator::Xorshift);
+        assert_eq!(config.palette.on, [0xff, 0x80, 0x00]);
+        assert_eq!(config.keymap.key(Button::Up), Some(5));
+
+        let mut user = RomSettings {
+            quirks: vec![("wrap".to_string(), false)],
+            tickrate: Some(50),
+            rng: Some(Generator::Rand),
+            seed: Some(1234),
+            keys: vec![("up".to_string(), 2)],
+            ..Default::default()
+        };
+        let config = RomConfig::resolve("", None, &[&database, &user]);
+        assert!(!config.quirks.wrap);
+        assert_eq!(config.cycles_per_frame, 50);
+        assert_eq!(
+            (config.generator, config.seed),
+            (Generator::Rand, Some(1234))
+        );
+        assert_eq!(config.keymap.key(Button::Up), Some(2));
+
+        user.platforms = vec!["xochip".to_string()];
+        let config = RomConfig::resolve("", None, &[&database, &user]);
+        assert!(config.quirks.logic);
+        assert_eq!(RomSettings::from_json(&user.to_json()).unwrap(), user);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::display::DISPLAY_SIZE;
+use crate::timestamp;
+use crate::WDW_HEIGHT;
+use crate::WDW_SIZE_SCALAR;
+use crate::WDW_WIDTH;
+use std::fs;
+use std::path::Path;
+
+/// Colours used for unlit and lit pixels when exporting an image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub off: [u8; 3],
+    pub on: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            off: [0, 0, 0],
+            on: [255, 255, 255],
+        }
+    }
+}
+
+/// Encodes the framebuffer as a raw (P4) PBM, one bit per pixel with lit
+/// pixels stored as 1.
+pub fn encode_pbm(display: &[bool; DISPLAY_SIZE]) -> Vec<u8> {
+    let mut out = format!("P4\n{} {}\n", WDW_WIDTH, WDW_HEIGHT).into_bytes();
+    out.extend_from_slice(&pack_pixels(display));
+    out
+}
+
+/// Packs the framebuffer eight pixels to a byte, most significant bit first.
+pub fn pack_pixels(display: &[bool; DISPLAY_SIZE]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(DISPLAY_SIZE / 8);
+    for byte in display.chunks(8) {
+        let mut packed = 0u8;
+        for (bit, &lit) in byte.iter().enumerate() {
+            if lit {
+                packed |= 0b1000_0000 >> bit;
+            }
+        }
+        out.push(packed);
+    }
+    out
+}
+
+/// Encodes the framebuffer as an indexed-colour PNG, with every emulated
+/// pixel drawn as a `scale` x `scale` block.
+pub fn encode_png(display: &[bool; DISPLAY_SIZE], palette: Palette, scale: u32) -> Vec<u8> {
+    let scale = scale.max(1);
+    let width = WDW_WIDTH * scale;
+    let height = WDW_HEIGHT * scale;
+
+    // Each scanline starts with filter type 0 followed by one palette index
+    // per pixel.
+    let mut raw = Vec::with_capacity(((width + 1) * height) as usize);
+    for y in 0..height {
+        raw.push(0u8);
+        for x in 0..width {
+            let i = (WDW_WIDTH * (y / scale) + (x / scale)) as usize;
+            raw.push(display[i] as u8);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 3 (indexed), default compression, filter and
+    // no interlacing
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+
+    let mut plte = Vec::new();
+    plte.extend_from_slice(&palette.off);
+    plte.extend_from_slice(&palette.on);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"PLTE", &plte);
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Writes the framebuffer to `path`, picking PBM for a `.pbm` extension and
+/// PNG for anything else.
+pub fn save(
+    path: &Path,
+    display: &[bool; DISPLAY_SIZE],
+    palette: Palette,
+    scale: u32,
+) -> Result<(), String> {
+    let data = match path.extension().and_then(|e| e.to_str()) {
+        Some("pbm") => encode_pbm(display),
+        _ => encode_png(display, palette, scale),
+    };
+    fs::write(path, data).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Saves the screen as both a PNG and a PBM named after the current time.
+pub fn save_timestamped(display: &[bool; DISPLAY_SIZE], palette: Palette) -> Result<(), String> {
+    let stamp = timestamp();
+    for extension in ["png", "pbm"] {
+        let file_name = format!("screenshot-{}.{}", stamp, extension);
+        save(Path::new(&file_name), display, palette, WDW_SIZE_SCALAR)?;
+        println!("Saved {}", file_name);
+    }
+    Ok(())
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream made of uncompressed deflate blocks.  The
+/// images are tiny, so there is no point in actually compressing them.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+    #[test]
+    fn test_pbm() {
+        let mut display = [false; DISPLAY_SIZE];
+        display[0] = true;
+        display[WDW_WIDTH as usize + 9] = true;
+        let pbm = encode_pbm(&display);
+        let header = b"P4\n64 32\n";
+        assert_eq!(&pbm[..header.len()], header);
+        let body = &pbm[header.len()..];
+        assert_eq!(body.len(), DISPLAY_SIZE / 8);
+        assert_eq!(body[0], 0b1000_0000);
+        assert_eq!(body[9], 0b0100_0000);
+    }
+}
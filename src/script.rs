@@ -0,0 +1,317 @@
+use crate::machine::ChipMachine;
+#[cfg(feature = "scripting")]
+use crate::machine::ChipMemory;
+#[cfg(feature = "scripting")]
+use crate::screenshot::{self, Palette};
+#[cfg(feature = "scripting")]
+use crate::watchpoints::Register;
+#[cfg(feature = "scripting")]
+use crate::{WDW_HEIGHT, WDW_SIZE_SCALAR, WDW_WIDTH};
+#[cfg(feature = "scripting")]
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+#[cfg(feature = "scripting")]
+use std::cell::RefCell;
+use std::path::Path;
+#[cfg(feature = "scripting")]
+use std::rc::Rc;
+
+#[cfg(feature = "scripting")]
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What the script's functions work on.
+#[cfg(feature = "scripting")]
+struct State {
+    /// The frontend's machine while the script is running, and a spare one
+    /// the rest of the time.
+    machine: ChipMachine,
+    /// Keys the script is holding down, kept pressed whatever the frontend
+    /// reads from the keyboard.
+    held: [bool; 16],
+    frame_hooks: Vec<FnPtr>,
+    instruction_hooks: Vec<FnPtr>,
+    /// Frames run since the script started.
+    frames: i64,
+}
+
+#[cfg(feature = "scripting")]
+impl State {
+    fn press_held(&mut self) {
+        for (input, held) in self.machine.input.iter_mut().zip(self.held) {
+            *input |= held;
+        }
+    }
+
+    /// A frame the script runs itself, with no hooks called.
+    fn run_frame(&mut self) -> Result<(), String> {
+        self.press_held();
+        let machine = &mut self.machine;
+        machine.run_cycles(machine.cycles_per_frame)?;
+        machine.tick_timers();
+        machine.take_sound_update();
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+/// A Rhai script driving the machine, for bots and test scenarios.  It can
+/// read and write the registers and RAM, hold keys down, run frames, take
+/// screenshots, and have functions called every frame or instruction:
+///
+/// ```text
+/// on_frame(|| if frame_count() % 30 == 0 { press(5) } else { release(5) });
+/// frames(600);
+/// if peek(0x3A0) != 3 { throw "expected 3 lives"; }
+/// screenshot("end.png");
+/// ```
+#[cfg(feature = "scripting")]
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<State>>,
+}
+
+#[cfg(feature = "scripting")]
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Self::compile(&source).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let state = Rc::new(RefCell::new(State {
+            machine: ChipMachine::new(ChipMemory::new()),
+            held: [false; 16],
+            frame_hooks: Vec::new(),
+            instruction_hooks: Vec::new(),
+            frames: 0,
+        }));
+        let mut engine = Engine::new();
+        register_functions(&mut engine, &state);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        Ok(Self { engine, ast, state })
+    }
+
+    /// Runs the script's top level, which sets up its hooks and may run
+    /// the machine itself.
+    pub fn run(&mut self, machine: &mut ChipMachine) -> Result<(), String> {
+        self.with_machine(machine, |script| {
+            script
+                .engine
+                .run_ast(&script.ast)
+                .map_err(|e| format!("Script error: {}", e))
+        })
+    }
+
+    /// Calls the frame hooks, before a frame's instructions run.
+    pub fn frame(&mut self, machine: &mut ChipMachine) -> Result<(), String> {
+        self.with_machine(machine, |script| {
+            let hooks = {
+                let mut state = script.state.borrow_mut();
+                state.frames += 1;
+                state.press_held();
+                state.frame_hooks.clone()
+            };
+            script.call(&hooks)
+        })
+    }
+
+    /// Runs instructions as `ChipMachine::run_cycles` does, calling the
+    /// instruction hooks before each one.
+    pub fn run_cycles(&mut self, machine: &mut ChipMachine, cycles: u32) -> Result<bool, String> {
+        let hooks = self.state.borrow().instruction_hooks.clone();
+        if hooks.is_empty() {
+            return machine.run_cycles(cycles);
+        }
+        self.with_machine(machine, |script| {
+            for _ in 0..cycles {
+                script.call(&hooks)?;
+                if !script.state.borrow_mut().machine.run_cycles(1)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn call(&self, hooks: &[FnPtr]) -> Result<(), String> {
+        for hook in hooks {
+            let _: Dynamic = hook
+                .call(&self.engine, &self.ast, ())
+                .map_err(|e| format!("Script error in {}: {}", hook.fn_name(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Lends the script `machine` for the length of `f`.
+    fn with_machine<T>(
+        &mut self,
+        machine: &mut ChipMachine,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        std::mem::swap(machine, &mut self.state.borrow_mut().machine);
+        let result = f(self);
+        std::mem::swap(machine, &mut self.state.borrow_mut().machine);
+        result
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn register_functions(engine: &mut Engine, state: &Rc<RefCell<State>>) {
+    fn register(name: &str) -> ScriptResult<Register> {
+        Register::parse(name).ok_or_else(|| format!("No register {}", name).into())
+    }
+    fn address(address: i64) -> ScriptResult<usize> {
+        usize::try_from(address)
+            .ok()
+            .filter(|&a| a < 4096)
+            .ok_or_else(|| format!("Invalid address {}", address).into())
+    }
+    fn key(key: i64) -> ScriptResult<usize> {
+        usize::try_from(key)
+            .ok()
+            .filter(|&k| k < 16)
+            .ok_or_else(|| format!("Invalid key {}", key).into())
+    }
+
+    let s = state.clone();
+    engine.register_fn("peek", move |at: i64| -> ScriptResult<i64> {
+        Ok(s.borrow().machine.mem.ram[address(at)?] as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("poke", move |at: i64, value: i64| -> ScriptResult<()> {
+        s.borrow_mut().machine.mem.ram[address(at)?] = value as u8;
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("reg", move |name: &str| -> ScriptResult<i64> {
+        Ok(register(name)?.value(&s.borrow().machine.mem) as i64)
+    });
+    let s = state.clone();
+    engine.register_fn(
+        "set_reg",
+        move |name: &str, value: i64| -> ScriptResult<()> {
+            register(name)?.set(&mut s.borrow_mut().machine.mem, value as u16);
+            Ok(())
+        },
+    );
+    let s = state.clone();
+    engine.register_fn("press", move |k: i64| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        let k = key(k)?;
+        state.held[k] = true;
+        state.machine.input[k] = true;
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("release", move |k: i64| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        let k = key(k)?;
+        state.held[k] = false;
+        state.machine.input[k] = false;
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| -> bool {
+        let state = s.borrow();
+        let (x, y) = (
+            x.rem_euclid(WDW_WIDTH as i64),
+            y.rem_euclid(WDW_HEIGHT as i64),
+        );
+        state.machine.display.pixels()[y as usize * WDW_WIDTH as usize + x as usize]
+    });
+    let s = state.clone();
+    engine.register_fn("step", move || -> ScriptResult<()> {
+        Ok(s.borrow_mut().machine.chip_clk()?)
+    });
+    let s = state.clone();
+    engine.register_fn("frame", move || -> ScriptResult<()> {
+        Ok(s.borrow_mut().run_frame()?)
+    });
+    let s = state.clone();
+    engine.register_fn("frames", move |n: i64| -> ScriptResult<()> {
+        let mut state = s.borrow_mut();
+        for _ in 0..n {
+            state.run_frame()?;
+        }
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("frame_count", move || s.borrow().frames);
+    let s = state.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        let state = s.borrow();
+        let pixels = state.machine.display.pixels();
+        Ok(screenshot::save(
+            Path::new(path),
+            pixels,
+            Palette::default(),
+            WDW_SIZE_SCALAR,
+        )?)
+    });
+    let s = state.clone();
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        s.borrow_mut().frame_hooks.push(hook)
+    });
+    let s = state.clone();
+    engine.register_fn("on_instruction", move |hook: FnPtr| {
+        s.borrow_mut().instruction_hooks.push(hook)
+    });
+}
+
+/// Stands in for scripts in builds without them, and can't be made.
+#[cfg(not(feature = "scripting"))]
+pub enum Script {}
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn load(_path: &Path) -> Result<Self, String> {
+        Err("Built without scripting support".to_string())
+    }
+    pub fn run(&mut self, _machine: &mut ChipMachine) -> Result<(), String> {
+        match *self {}
+    }
+    pub fn frame(&mut self, _machine: &mut ChipMachine) -> Result<(), String> {
+        match *self {}
+    }
+    pub fn run_cycles(&mut self, _machine: &mut ChipMachine, _cycles: u32) -> Result<bool, String> {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_script() {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        // 0x200: v0 += 1, 0x202: if v1 -key then jump 0x200, 0x206: v2 := 7
+        machine
+            .load_rom_bytes(&[0x70, 0x01, 0xE1, 0x9E, 0x12, 0x00, 0x62, 0x07])
+            .unwrap();
+        let mut script = Script::compile(
+            r#"
+            on_instruction(|| if reg("PC") == 0x206 { poke(0x300, reg("V0")) });
+            on_frame(|| if frame_count() == 2 { press(0) });
+            frames(1);
+            if reg("V0") != 4 { throw "v0 is " + reg("V0"); }
+            set_reg("V1", 0);
+            "#,
+        )
+        .unwrap();
+        script.run(&mut machine).unwrap();
+        assert_eq!(machine.mem.registers[0], 4);
+
+        // The second frame's hook holds key 0, so the loop ends
+        script.frame(&mut machine).unwrap();
+        assert!(script.run_cycles(&mut machine, 3).unwrap());
+        assert_eq!(machine.mem.registers[2], 7);
+        assert_eq!(machine.mem.ram[0x300], 5);
+
+        let mut failing = Script::compile("peek(5000)").unwrap();
+        assert!(failing
+            .run(&mut machine)
+            .unwrap_err()
+            .contains("Invalid address 5000"));
+    }
+}
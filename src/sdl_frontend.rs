@@ -0,0 +1,622 @@
+use crate::audio::{
+    open_device, AudioBackend, AudioPattern, BeepConfig, ScheduledTone, SoundEvent, ToneGenerator,
+    WavSink,
+};
+use crate::browser::RomBrowser;
+use crate::disasm;
+use crate::gdbstub::GdbStub;
+use crate::machine::{ChipMachine, ChipMemory};
+use crate::movie::MovieRecorder;
+use crate::phosphor::Persistence;
+use crate::recorder::Recorder;
+use crate::renderer::Renderer;
+use crate::rom_config::{Button, Keymap, RomConfig, RomConfigStore};
+use crate::screenshot::{self, Palette};
+use crate::script::Script;
+use crate::timestamp;
+use crate::trace::Tracer;
+use crate::watch::{self, HotReload};
+use crate::RECORDING_SCALAR;
+use crate::WDW_HEIGHT;
+use crate::WDW_SIZE_SCALAR;
+use crate::WDW_WIDTH;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How far behind emulated time the audio callback plays, so events reach
+/// it before their moment comes round.
+const AUDIO_LATENCY: f64 = 0.05;
+/// If the callback falls further behind than this, it skips ahead.
+const AUDIO_MAX_LAG: f64 = 0.25;
+
+/// Plays the beeper through an SDL audio device.
+struct SdlAudio {
+    playing: bool,
+    device: sdl2::audio::AudioDevice<ChipBeep>,
+}
+struct ChipBeep {
+    tone: ScheduledTone,
+    sample_period: f64,
+    /// Emulated time of the next sample, `None` until the machine first
+    /// reports its time.
+    clock: Option<f64>,
+    /// The furthest the machine has got.  Playback never runs past it.
+    latest: f64,
+}
+impl sdl2::audio::AudioCallback for ChipBeep {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        for x in out.iter_mut() {
+            *x = match self.clock {
+                Some(clock) => {
+                    self.clock = Some((clock + self.sample_period).min(self.latest));
+                    self.tone.sample_at(clock)
+                }
+                None => self.tone.tone.next_sample(),
+            };
+        }
+    }
+}
+
+impl SdlAudio {
+    fn open(audio_subsystem: sdl2::AudioSubsystem, config: BeepConfig) -> Result<Self, String> {
+        let device = audio_subsystem.open_playback(
+            None,
+            &sdl2::audio::AudioSpecDesired {
+                freq: Some(44100),
+                channels: Some(1),
+                // Small buffers keep the callback close to the emulated
+                // timeline
+                samples: Some(512),
+            },
+            |spec| ChipBeep {
+                tone: ScheduledTone::new(ToneGenerator::new(config, spec.freq as u32)),
+                sample_period: 1.0 / spec.freq as f64,
+                clock: None,
+                latest: 0.0,
+            },
+        )?;
+        // The device runs for the whole session and the beep is gated inside
+        // the callback, so starting and stopping it can ramp instead of click.
+        device.resume();
+        Ok(Self {
+            playing: false,
+            device,
+        })
+    }
+}
+
+impl AudioBackend for SdlAudio {
+    fn push_event(&mut self, event: SoundEvent) {
+        self.device.lock().tone.push_event(event);
+        self.playing = event.on;
+    }
+    fn advance_to(&mut self, time: f64) {
+        let mut beep = self.device.lock();
+        beep.latest = time;
+        match beep.clock {
+            Some(clock) if time - clock <= AUDIO_MAX_LAG => {}
+            _ => beep.clock = Some(time - AUDIO_LATENCY),
+        }
+    }
+    fn playing(&self) -> bool {
+        self.playing
+    }
+    fn config(&mut self) -> BeepConfig {
+        self.device.lock().tone.tone.config()
+    }
+    fn set_config(&mut self, config: BeepConfig) {
+        self.device.lock().tone.tone.set_config(config);
+    }
+    fn muted(&mut self) -> bool {
+        self.device.lock().tone.tone.muted()
+    }
+    fn set_muted(&mut self, muted: bool) {
+        self.device.lock().tone.tone.set_muted(muted);
+    }
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.device.lock().tone.tone.set_pattern(pattern);
+    }
+}
+
+pub struct ChipEmulator {
+    machine: ChipMachine,
+    renderer: Renderer,
+    sdl_context: sdl2::Sdl,
+    audio: Box<dyn AudioBackend>,
+    pub recorder: Option<Recorder>,
+    store: RomConfigStore,
+    palette: Palette,
+    keymap: Keymap,
+    /// The ROM running, to reload on reset.
+    rom_path: Option<String>,
+    /// Where the ROM browser looks.
+    rom_dir: PathBuf,
+    /// Shown in place of the game while a ROM is being picked.
+    browser: Option<RomBrowser>,
+    /// Set in watch mode.
+    hot_reload: Option<HotReload>,
+    /// Breakpoints given on the command line, as labels or addresses.
+    breakpoints: Vec<String>,
+    /// Set when a debugger may attach, which then says when to run.
+    gdb: Option<GdbStub>,
+    /// Driving the ROM alongside the keyboard, when given.
+    script: Option<Script>,
+    /// Set while recording a movie.
+    movie: Option<MovieRecorder>,
+    /// The fade P switches to.
+    fade_frames: u8,
+}
+
+impl ChipEmulator {
+    /// Opens the window and the sound device, or renders the beeper to `wav`
+    /// instead when given.  ROMs are set up as `store` says, and the ROM
+    /// browser lists those in `rom_dir`.
+    pub fn new(
+        mem: ChipMemory,
+        beep: BeepConfig,
+        muted: bool,
+        wav: Option<&Path>,
+        store: RomConfigStore,
+        rom_dir: &Path,
+    ) -> Result<Self, String> {
+        let sdl_context = sdl2::init()?;
+        let video_subsystem = sdl_context.video()?;
+        let window = video_subsystem
+            .window(
+                "Rust SDL Demo",
+                WDW_WIDTH * WDW_SIZE_SCALAR,
+                WDW_HEIGHT * WDW_SIZE_SCALAR,
+            )
+            .position_centered()
+            .opengl()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let renderer = Renderer::new(window)?;
+        let mut audio: Box<dyn AudioBackend> = match wav {
+            Some(path) => Box::new(WavSink::new(path, beep)),
+            None => open_device(beep, muted, |config| {
+                let subsystem = sdl_context.audio()?;
+                Ok(Box::new(SdlAudio::open(subsystem, config)?))
+            }),
+        };
+        audio.set_muted(muted);
+        // let pump = sdl_context.event_pump()?;
+        Ok(Self {
+            machine: ChipMachine::new(mem),
+            renderer,
+            sdl_context,
+            audio,
+            recorder: None,
+            store,
+            palette: Palette::default(),
+            keymap: Keymap::default(),
+            rom_path: None,
+            rom_dir: rom_dir.to_path_buf(),
+            browser: None,
+            hot_reload: None,
+            breakpoints: Vec::new(),
+            gdb: None,
+            script: None,
+            movie: None,
+            fade_frames: Persistence::DEFAULT_FADE_FRAMES,
+        })
+    }
+
+    pub fn run_loop(&mut self) -> Result<(), String> {
+        let mut pump = self.sdl_context.event_pump()?;
+        let mut current_time = 0u64;
+        let mut auto_clk = true;
+        let mut last_delay_time = 0u64;
+        // Instructions owed, as the ROM's tickrate rarely divides evenly
+        // into the loop's iterations
+        let mut cycle_budget = 0.0f64;
+        'running: loop {
+            for event in pump.poll_iter() {
+                if self.browser.is_some() {
+                    match event {
+                        Event::Quit { .. } => break 'running,
+                        Event::KeyDown {
+                            keycode: Some(keycode),
+                            ..
+                        } if !self.browser_key(keycode)? => break 'running,
+                        _ => {}
+                    }
+                    continue;
+                }
+                match event {
+                    Event::Quit { .. } => break 'running,
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        ..
+                    } => match keycode {
+                        Keycode::Escape => break 'running,
+                        Keycode::Space => {
+                            self.machine.chip_clk()?;
+                            self.print_location();
+                        }
+                        Keycode::M => auto_clk = !auto_clk,
+                        Keycode::K => {
+                            let pc = self.machine.mem.pc;
+                            let set = self.machine.toggle_breakpoint(pc);
+                            println!(
+                                "Breakpoint at {:#06x}: {}",
+                                pc,
+                                if set { "on" } else { "off" }
+                            );
+                        }
+                        Keycode::Num1 => self.machine.input[0x1] = true,
+                        Keycode::Num2 => self.machine.input[0x2] = true,
+                        Keycode::Num3 => self.machine.input[0x3] = true,
+                        Keycode::Q => self.machine.input[0x4] = true,
+                        Keycode::W => self.machine.input[0x5] = true,
+                        Keycode::E => self.machine.input[0x6] = true,
+                        Keycode::A => self.machine.input[0x7] = true,
+                        Keycode::S => self.machine.input[0x8] = true,
+                        Keycode::D => self.machine.input[0x9] = true,
+                        Keycode::Z => self.machine.input[0xA] = true,
+                        Keycode::C => self.machine.input[0xB] = true,
+                        Keycode::Num4 => self.machine.input[0xC] = true,
+                        Keycode::R => self.machine.input[0xD] = true,
+                        Keycode::F => self.machine.input[0xE] = true,
+                        Keycode::V => self.machine.input[0xF] = true,
+                        Keycode::X => self.machine.input[0x0] = true,
+                        Keycode::Up
+                        | Keycode::Down
+                        | Keycode::Left
+                        | Keycode::Right
+                        | Keycode::Return
+                        | Keycode::Backspace => self.press_button(keycode, true),
+                        Keycode::N => self.machine.display.print_debug(),
+                        Keycode::F12 => self.save_screenshot()?,
+                        Keycode::F5 => self.reset_rom()?,
+                        Keycode::O => self.open_browser(),
+                        Keycode::F9 => self.toggle_recording("gif")?,
+                        Keycode::F10 => self.toggle_recording("raw")?,
+                        Keycode::B => {
+                            let muted = !self.audio.muted();
+                            println!("Muted: {}", muted);
+                            self.audio.set_muted(muted);
+                        }
+                        Keycode::G => {
+                            let mut config = self.audio.config();
+                            config.waveform = config.waveform.next();
+                            println!("Waveform: {:?}", config.waveform);
+                            self.audio.set_config(config);
+                        }
+                        Keycode::LeftBracket
+                        | Keycode::RightBracket
+                        | Keycode::Semicolon
+                        | Keycode::Quote
+                        | Keycode::Minus
+                        | Keycode::Equals => self.adjust_beep(keycode),
+                        Keycode::P => {
+                            let next = match self.renderer.persistence() {
+                                Persistence::Off => Persistence::Blend,
+                                Persistence::Blend => Persistence::Fade(self.fade_frames),
+                                Persistence::Fade(_) => Persistence::Off,
+                            };
+                            println!("Persistence: {:?}", next);
+                            self.renderer.set_persistence(next);
+                        }
+                        _ => {}
+                    },
+                    Event::KeyUp {
+                        keycode: Some(keycode),
+                        ..
+                    } => match keycode {
+                        Keycode::Num1 => self.machine.input[0x1] = false,
+                        Keycode::Num2 => self.machine.input[0x2] = false,
+                        Keycode::Num3 => self.machine.input[0x3] = false,
+                        Keycode::Q => self.machine.input[0x4] = false,
+                        Keycode::W => self.machine.input[0x5] = false,
+                        Keycode::E => self.machine.input[0x6] = false,
+                        Keycode::A => self.machine.input[0x7] = false,
+                        Keycode::S => self.machine.input[0x8] = false,
+                        Keycode::D => self.machine.input[0x9] = false,
+                        Keycode::Z => self.machine.input[0xA] = false,
+                        Keycode::C => self.machine.input[0xB] = false,
+                        Keycode::Num4 => self.machine.input[0xC] = false,
+                        Keycode::R => self.machine.input[0xD] = false,
+                        Keycode::F => self.machine.input[0xE] = false,
+                        Keycode::V => self.machine.input[0xF] = false,
+                        Keycode::X => self.machine.input[0x0] = false,
+                        Keycode::Up
+                        | Keycode::Down
+                        | Keycode::Left
+                        | Keycode::Right
+                        | Keycode::Return
+                        | Keycode::Backspace => self.press_button(keycode, false),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 720)); // Renders 180 times
+                                                                            // per second
+            current_time += 1_000_000_000u64 / 720;
+            if let Some(browser) = &self.browser {
+                let (rows, columns) = self.renderer.text_size();
+                self.renderer.draw_text(&browser.lines(rows, columns))?;
+                continue;
+            }
+            if let Some(hot_reload) = &mut self.hot_reload {
+                if let Some(config) = hot_reload.poll(&mut self.machine, &self.store) {
+                    self.apply_config(config);
+                }
+            }
+            if let Some(gdb) = &mut self.gdb {
+                gdb.poll(&mut self.machine)?;
+                auto_clk = gdb.running();
+            }
+            let delay_delta_time = current_time - last_delay_time;
+            // Tick timers sixty times per second
+            if delay_delta_time > 1_000_000_000u64 / 60 {
+                last_delay_time = current_time;
+                if let (true, Some(script)) = (auto_clk, &mut self.script) {
+                    script.frame(&mut self.machine)?;
+                }
+                if let Some(movie) = &mut self.movie {
+                    movie.frame(&self.machine);
+                }
+                self.machine.tick_timers();
+                if auto_clk {
+                    self.renderer.advance_phosphor(&self.machine.display);
+                }
+                let sound = self.machine.take_sound_update();
+                self.audio.apply(&sound);
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.capture(&self.machine.display, &sound);
+                }
+            }
+
+            if auto_clk {
+                cycle_budget += self.machine.cycles_per_frame as f64 * 60.0 / 720.0;
+                let cycles = cycle_budget.floor();
+                cycle_budget -= cycles;
+                if let Some(movie) = &mut self.movie {
+                    movie.input(&self.machine);
+                }
+                let running = match &mut self.script {
+                    Some(script) => script.run_cycles(&mut self.machine, cycles as u32)?,
+                    None => self.machine.run_cycles(cycles as u32)?,
+                };
+                if !running {
+                    println!("{}", self.machine.stop_reason().unwrap_or("Breakpoint"));
+                    self.print_location();
+                    auto_clk = false;
+                    cycle_budget = 0.0;
+                    if let Some(gdb) = &mut self.gdb {
+                        gdb.stopped();
+                    }
+                }
+                let beep_indicator = self.audio.playing() && !self.audio.audible();
+                self.renderer.draw(&self.machine.display, beep_indicator)?;
+            }
+        }
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
+        }
+        if let Some(movie) = self.movie.take() {
+            movie.finish()?;
+        }
+        self.audio.finish()
+    }
+
+    /// Changes the beep while running: `[` and `]` move the tone a semitone,
+    /// `;` and `'` the square wave's duty cycle, and `-` and `=` the volume.
+    fn adjust_beep(&mut self, keycode: Keycode) {
+        let mut config = self.audio.config();
+        match keycode {
+            Keycode::LeftBracket => config.transpose(-1),
+            Keycode::RightBracket => config.transpose(1),
+            Keycode::Semicolon => config.adjust_duty(-0.05),
+            Keycode::Quote => config.adjust_duty(0.05),
+            Keycode::Minus => config.adjust_volume(-0.05),
+            _ => config.adjust_volume(0.05),
+        }
+        println!(
+            "Tone {:.0} Hz, duty {:.2}, volume {:.2}",
+            config.frequency, config.duty, config.volume
+        );
+        self.audio.set_config(config);
+    }
+
+    /// Presses or releases the keypad key the ROM binds to a controller
+    /// button: the arrow keys, Return for A and Backspace for B.
+    fn press_button(&mut self, keycode: Keycode, down: bool) {
+        let button = match keycode {
+            Keycode::Up => Button::Up,
+            Keycode::Down => Button::Down,
+            Keycode::Left => Button::Left,
+            Keycode::Right => Button::Right,
+            Keycode::Return => Button::A,
+            _ => Button::B,
+        };
+        if let Some(key) = self.keymap.key(button) {
+            self.machine.input[key] = down;
+        }
+    }
+
+    /// Prints the next instruction and the calls that led to it.
+    fn print_location(&self) {
+        println!("{}", disasm::current_instruction(&self.machine));
+        let stack = self.machine.symbols.call_stack(&self.machine.mem);
+        if stack.len() > 1 {
+            println!("  called from {}", stack[1..].join(" < "));
+        }
+    }
+
+    pub fn save_screenshot(&self) -> Result<(), String> {
+        screenshot::save_timestamped(self.machine.display.pixels(), self.palette)
+    }
+
+    pub fn start_recording(&mut self, path: &Path) {
+        let mut recorder = Recorder::new(path, RECORDING_SCALAR, self.audio.config());
+        recorder.palette = self.palette;
+        self.recorder = Some(recorder);
+    }
+
+    /// Starts recording to a file named after the current time, or stops
+    /// and saves the recording already in progress.
+    pub fn toggle_recording(&mut self, extension: &str) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => {
+                let file_name = format!("recording-{}.{}", timestamp(), extension);
+                println!("Recording to {}", file_name);
+                self.start_recording(Path::new(&file_name));
+                Ok(())
+            }
+        }
+    }
+
+    /// Resets the machine and loads a ROM into it.
+    pub fn load_rom(&mut self, rom_path: &str) -> Result<(), String> {
+        self.machine.reset();
+        let config = self.store.load_rom(&mut self.machine, rom_path)?;
+        watch::set_breakpoints(&mut self.machine, &self.breakpoints);
+        self.apply_config(config);
+        self.rom_path = Some(rom_path.to_string());
+        if let Some(hot_reload) = &mut self.hot_reload {
+            hot_reload.watch(Path::new(rom_path));
+        }
+        Ok(())
+    }
+
+    fn apply_config(&mut self, config: RomConfig) {
+        self.palette = config.palette;
+        self.keymap = config.keymap;
+        self.renderer.set_palette(config.palette);
+    }
+
+    /// Sets the breakpoints to start with, found in each ROM as it loads.
+    pub fn set_breakpoints(&mut self, breakpoints: &[String]) {
+        self.breakpoints = breakpoints.to_vec();
+    }
+
+    /// Lets a debugger attach through `gdb`.
+    pub fn set_gdb(&mut self, gdb: Option<GdbStub>) {
+        self.gdb = gdb;
+    }
+
+    /// Records a movie of the loaded ROM from here on, written out when the
+    /// window closes.
+    pub fn record_movie(&mut self, path: &Path) {
+        self.movie = Some(MovieRecorder::start(path, &mut self.machine));
+    }
+
+    /// Runs `script`'s top level against the loaded ROM, then keeps it to
+    /// call its hooks.
+    pub fn set_script(&mut self, mut script: Option<Script>) -> Result<(), String> {
+        if let Some(script) = &mut script {
+            script.run(&mut self.machine)?;
+        }
+        self.script = script;
+        Ok(())
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.machine.tracer = tracer;
+    }
+
+    /// Reads labels from `path` rather than next to the ROM.
+    /// Sets how the screen hides flicker.  A fade's length is also what
+    /// P switches to.
+    pub fn set_persistence(&mut self, mode: Persistence) {
+        if let Persistence::Fade(frames) = mode {
+            self.fade_frames = frames;
+        }
+        self.renderer.set_persistence(mode);
+    }
+    pub fn set_symbol_file(&mut self, path: Option<PathBuf>) {
+        self.machine.symbol_file = path;
+    }
+
+    /// Turns on watch mode, reloading each ROM loaded from now on whenever
+    /// its file changes.  The machine is reset, but the window stays where it
+    /// is.
+    pub fn watch(&mut self, keep_breakpoints: bool) {
+        let path = Path::new(self.rom_path.as_deref().unwrap_or_default());
+        self.hot_reload = Some(HotReload::new(
+            path,
+            keep_breakpoints,
+            self.breakpoints.clone(),
+        ));
+    }
+
+    /// Starts the running ROM over from the beginning.
+    pub fn reset_rom(&mut self) -> Result<(), String> {
+        match self.rom_path.clone() {
+            Some(path) => self.load_rom(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Pauses the game behind a list of ROMs to pick another from.
+    pub fn open_browser(&mut self) {
+        self.browser = Some(RomBrowser::scan(&self.rom_dir, &self.store));
+        self.machine.input = [false; 16];
+        if self.machine.mem.timers.sound > 0 {
+            self.gate_sound(false);
+        }
+    }
+
+    fn close_browser(&mut self) {
+        self.browser = None;
+        if self.machine.mem.timers.sound > 0 {
+            self.gate_sound(true);
+        }
+    }
+
+    /// Stops or restarts a beep while the game is paused behind the browser.
+    fn gate_sound(&mut self, on: bool) {
+        let time = self.machine.emulated_time();
+        let mut sound = self.machine.take_sound_update();
+        sound.events.push(SoundEvent { time, on });
+        self.audio.apply(&sound);
+    }
+
+    /// Handles a key press while the browser is open.  Returns false once
+    /// the user asks to quit.
+    fn browser_key(&mut self, keycode: Keycode) -> Result<bool, String> {
+        let Some(browser) = &mut self.browser else {
+            return Ok(true);
+        };
+        let (rows, _) = self.renderer.text_size();
+        match keycode {
+            // The keypad's 2 and 8 are up and down, as in most games
+            Keycode::Up | Keycode::Num2 => browser.move_by(-1),
+            Keycode::Down | Keycode::S => browser.move_by(1),
+            Keycode::PageUp => browser.move_by(-(rows as isize)),
+            Keycode::PageDown => browser.move_by(rows as isize),
+            Keycode::Return | Keycode::W => {
+                if let Some(entry) = browser.selected() {
+                    let path = entry.path.display().to_string();
+                    // The machine is reset either way, so the old game
+                    // doesn't come back if this one fails to load
+                    self.browser = None;
+                    // A symbol file from the command line was for the
+                    // ROM given there
+                    self.machine.symbol_file = None;
+                    if let Err(e) = self.load_rom(&path) {
+                        println!("{}", e);
+                        self.rom_path = None;
+                        self.open_browser();
+                    }
+                }
+            }
+            Keycode::Escape => {
+                if self.rom_path.is_none() {
+                    return Ok(false);
+                }
+                self.close_browser();
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,216 @@
+use crate::machine::{ChipMachine, ChipMemory};
+use std::fmt;
+
+/// How many instructions after an `i := NNN` to look for the `sprite` that
+/// draws it.
+const LOOKAHEAD: usize = 8;
+
+/// The shape DXYN gives the bytes at I.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpriteFormat {
+    /// Rows, with 0 meaning the 16x16 sprite of SCHIP's DXY0.
+    pub height: u8,
+    /// 2 for an XO-CHIP sprite drawn to both planes, the second plane's
+    /// bytes following the first's.
+    pub planes: u8,
+}
+
+impl SpriteFormat {
+    pub fn width(self) -> usize {
+        if self.height == 0 {
+            16
+        } else {
+            8
+        }
+    }
+    pub fn rows(self) -> usize {
+        if self.height == 0 {
+            16
+        } else {
+            self.height as usize
+        }
+    }
+    /// Bytes the sprite takes up in RAM.
+    pub fn size(self) -> usize {
+        self.width() / 8 * self.rows() * self.planes as usize
+    }
+}
+
+impl fmt::Display for SpriteFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width(), self.rows())?;
+        if self.planes > 1 {
+            write!(f, ", {} planes", self.planes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sprite at `address` as DXYN would draw it, row by row.  Each pixel
+/// holds a bit for each plane it lights.
+pub fn decode(ram: &[u8], address: u16, format: SpriteFormat) -> Vec<Vec<u8>> {
+    let bytes_per_row = format.width() / 8;
+    let plane_len = bytes_per_row * format.rows();
+    let byte = |offset: usize| ram[(address as usize + offset) % ram.len()];
+    (0..format.rows())
+        .map(|row| {
+            (0..format.width())
+                .map(|x| {
+                    (0..format.planes as usize)
+                        .map(|plane| {
+                            let bits = byte(plane * plane_len + row * bytes_per_row + x / 8);
+                            (bits >> (7 - x % 8) & 1) << plane
+                        })
+                        .sum()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Likely sprites in `ram[start..end]`, in address order: wherever an
+/// `i := NNN` into that range is followed shortly by a `sprite`, with the
+/// size it is drawn at.  Both byte alignments are looked at, since code
+/// isn't always on even addresses.
+pub fn scan(ram: &[u8], start: usize, end: usize) -> Vec<(u16, SpriteFormat)> {
+    let end = end.min(ram.len());
+    let word = |at: usize| u16::from_be_bytes([ram[at], ram[at + 1]]);
+    let mut found: Vec<(u16, SpriteFormat)> = Vec::new();
+    for at in start..end.saturating_sub(1) {
+        let op = word(at);
+        let target = op & 0xFFF;
+        if op >> 12 != 0xA || !(start..end).contains(&(target as usize)) {
+            continue;
+        }
+        for next in (1..=LOOKAHEAD).map(|n| at + 2 * n) {
+            if next + 1 >= end {
+                break;
+            }
+            let op = word(next);
+            match op >> 12 {
+                0xD => {
+                    let format = SpriteFormat {
+                        height: (op & 0xF) as u8,
+                        planes: 1,
+                    };
+                    if !found.iter().any(|&(address, _)| address == target) {
+                        found.push((target, format));
+                    }
+                    break;
+                }
+                // I is set again, or the code goes elsewhere
+                0xA | 0x1 | 0x2 | 0xB => break,
+                _ if op == 0x00EE => break,
+                _ => {}
+            }
+        }
+    }
+    found.sort_by_key(|&(address, _)| address);
+    found
+}
+
+/// The sprite viewer: the bytes at an address drawn in a chosen format,
+/// and the sprites found in the ROM to step through.
+#[derive(Clone, Debug)]
+pub struct SpriteView {
+    pub address: u16,
+    pub format: SpriteFormat,
+    pub found: Vec<(u16, SpriteFormat)>,
+}
+
+impl SpriteView {
+    /// Starts at I, drawn the way the instruction at the PC would if it is
+    /// a `sprite`.
+    pub fn new(machine: &ChipMachine) -> Self {
+        let mem = &machine.mem;
+        let pc = mem.pc as usize % mem.ram.len();
+        let op = u16::from_be_bytes([mem.ram[pc], mem.ram[(pc + 1) % mem.ram.len()]]);
+        let height = if op >> 12 == 0xD { op & 0xF } else { 5 };
+        Self {
+            address: mem.i % mem.ram.len() as u16,
+            format: SpriteFormat {
+                height: height as u8,
+                planes: 1,
+            },
+            found: scan(
+                &mem.ram,
+                ChipMemory::ROM_STARTING_MEMORY_LOCATION,
+                mem.ram.len(),
+            ),
+        }
+    }
+
+    pub fn move_by(&mut self, delta: i32) {
+        self.address = (self.address as i32 + delta).rem_euclid(4096) as u16;
+    }
+    /// Steps through the heights DXYN can draw, 16x16 coming after 15 rows.
+    pub fn change_height(&mut self, delta: i32) {
+        let height = (self.format.height as i32 + 15) % 16 + 1;
+        self.format.height = ((height + delta - 1).rem_euclid(16) + 1) as u8 % 16;
+    }
+    pub fn toggle_planes(&mut self) {
+        self.format.planes = 3 - self.format.planes;
+    }
+    /// Jumps to the next sprite found in the ROM after the address, or the
+    /// one before it.
+    pub fn next_found(&mut self, forward: bool) {
+        let next = if forward {
+            self.found.iter().find(|(a, _)| *a > self.address)
+        } else {
+            self.found.iter().rev().find(|(a, _)| *a < self.address)
+        };
+        if let Some(&(address, format)) = next {
+            self.address = address;
+            self.format = SpriteFormat {
+                planes: self.format.planes,
+                ..format
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    #[test]
+    fn test_sprites() {
+        let program = assemble(
+            "
+            : main
+              i := ship
+              v0 := 1
+              sprite v0 v0 3
+              i := big
+              sprite v0 v0 0
+              loop again
+            : ship
+              0x18 0x3C 0xFF
+            : big
+            ",
+        )
+        .unwrap();
+        let mut mem = ChipMemory::new();
+        let start = ChipMemory::ROM_STARTING_MEMORY_LOCATION;
+        mem.ram[start..start + program.bytes.len()].copy_from_slice(&program.bytes);
+        let (ship, big) = (
+            program.label_address("ship").unwrap(),
+            program.label_address("big").unwrap(),
+        );
+        let found = scan(&mem.ram, start, start + program.bytes.len() + 32);
+        let format = |height| SpriteFormat { height, planes: 1 };
+        assert_eq!(found, [(ship, format(3)), (big, format(0))]);
+        assert_eq!(format(0).to_string(), "16x16");
+
+        let rows = decode(&mem.ram, ship, format(3));
+        assert_eq!(rows[0], [0, 0, 0, 1, 1, 0, 0, 0]);
+        let two_planes = SpriteFormat {
+            height: 1,
+            planes: 2,
+        };
+        assert_eq!(
+            decode(&mem.ram, ship, two_planes)[0],
+            [0, 0, 2, 3, 3, 2, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::asm::Program;
+use crate::json::Json;
+use crate::machine::ChipMemory;
+use crate::options::parse_address;
+use std::fs;
+use std::path::Path;
+
+/// Names for addresses in the loaded program, from the assembler or a
+/// symbol file.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// In address order.
+    labels: Vec<(u16, String)>,
+}
+
+impl Symbols {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Reads either a JSON object of labels to addresses, or lines of an
+    /// address in hex followed by its label.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut labels = Vec::new();
+        if text.trim_start().starts_with('{') {
+            let json = Json::parse(text)?;
+            for (name, value) in json.members() {
+                let address = match value {
+                    Json::Number(n) if (0.0..4096.0).contains(n) => *n as u16,
+                    Json::String(s) => parse_address(s)?,
+                    _ => return Err(format!("{} doesn't have an address", name)),
+                };
+                labels.push((address, name.clone()));
+            }
+        } else {
+            for (n, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("");
+                let mut words = line.split_whitespace();
+                let (Some(address), Some(name)) = (words.next(), words.next()) else {
+                    continue;
+                };
+                let address =
+                    parse_address(address).map_err(|e| format!("line {}: {}", n + 1, e))?;
+                labels.push((address, name.to_string()));
+            }
+        }
+        Ok(Self::from_labels(labels))
+    }
+
+    pub fn from_program(program: &Program) -> Self {
+        Self::from_labels(
+            program
+                .labels
+                .iter()
+                .map(|(name, address)| (*address, name.clone()))
+                .collect(),
+        )
+    }
+
+    fn from_labels(mut labels: Vec<(u16, String)>) -> Self {
+        labels.sort();
+        Self { labels }
+    }
+
+    /// The symbol file format `parse` reads back.
+    pub fn to_text(&self) -> String {
+        self.labels
+            .iter()
+            .map(|(address, name)| format!("{:#06x} {}\n", address, name))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, label)| label == name)
+            .map(|&(address, _)| address)
+    }
+    /// The label at exactly `address`.
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        let i = self.labels.partition_point(|&(a, _)| a < address);
+        self.labels
+            .get(i)
+            .filter(|(a, _)| *a == address)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// `address` as an offset from the nearest label before it, like
+    /// `main+4`.
+    pub fn locate(&self, address: u16) -> Option<String> {
+        let i = self.labels.partition_point(|&(a, _)| a <= address);
+        i.checked_sub(1)
+            .map(|i| &self.labels[i])
+            .map(|(a, name)| match address - a {
+                0 => name.clone(),
+                offset => format!("{}+{}", name, offset),
+            })
+    }
+    /// `locate`, or the address in hex when there is no label before it.
+    pub fn describe(&self, address: u16) -> String {
+        self.locate(address)
+            .unwrap_or_else(|| format!("{:#06x}", address))
+    }
+
+    /// Where the program is and the calls that led there, innermost first.
+    pub fn call_stack(&self, mem: &ChipMemory) -> Vec<String> {
+        let mut frames = vec![self.describe(mem.pc)];
+        let depth = mem.stack_ptr.min(mem.stack.len());
+        for &ret in mem.stack[..depth].iter().rev() {
+            // The return address is just past the call
+            frames.push(self.describe(ret.wrapping_sub(2)));
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_symbols() {
+        let lines = Symbols::parse("# labels\n0x200 main\n20a draw\n").unwrap();
+        let json = Symbols::parse(r#"{"main": 512, "draw": "0x20a"}"#).unwrap();
+        for symbols in [&lines, &json] {
+            assert_eq!(symbols.address("draw"), Some(0x20A));
+            assert_eq!(symbols.name_at(0x200), Some("main"));
+            assert_eq!(symbols.describe(0x206), "main+6");
+            assert_eq!(symbols.describe(0x1FE), "0x01fe");
+        }
+        assert_eq!(
+            Symbols::parse(&lines.to_text()).unwrap().labels,
+            lines.labels
+        );
+
+        let mut mem = ChipMemory::new();
+        mem.pc = 0x20C;
+        mem.stack[0] = 0x204;
+        mem.stack_ptr = 1;
+        assert_eq!(lines.call_stack(&mem), ["draw+2", "main+2"]);
+    }
+}
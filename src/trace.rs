@@ -0,0 +1,150 @@
+use crate::disasm::disassemble;
+use crate::machine::ChipMemory;
+use crate::symbols::Symbols;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// What each line of a trace holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// The instruction run and the registers it changed, for reading.
+    Text,
+    /// The whole machine state before each instruction, in the portable
+    /// form `difftest` compares.
+    State,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "state" => Ok(TraceFormat::State),
+            _ => Err(format!("Unknown trace format {}", s)),
+        }
+    }
+}
+
+/// The registers an instruction may change, taken before it runs.
+pub struct Snapshot {
+    registers: [u8; 16],
+    i: u16,
+}
+
+impl Snapshot {
+    pub fn take(mem: &ChipMemory) -> Self {
+        Self {
+            registers: mem.registers,
+            i: mem.i,
+        }
+    }
+}
+
+/// Logs a line for every instruction executed, for comparing runs against
+/// other interpreters.
+pub struct Tracer {
+    pub format: TraceFormat,
+    out: Box<dyn Write + Send>,
+    /// Only instructions at these addresses are logged.
+    range: RangeInclusive<u16>,
+    /// Lines left before the trace stops, when limited.
+    remaining: Option<u64>,
+}
+
+impl Tracer {
+    /// Traces to the file at `path`, or to stdout for `-`.
+    pub fn open(
+        path: &str,
+        format: TraceFormat,
+        range: Option<RangeInclusive<u16>>,
+        limit: Option<u64>,
+    ) -> Result<Self, String> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file =
+                File::create(path).map_err(|e| format!("Unable to create {}: {}", path, e))?;
+            Box::new(BufWriter::new(file))
+        };
+        Ok(Self {
+            format,
+            out,
+            range: range.unwrap_or(0..=u16::MAX),
+            remaining: limit,
+        })
+    }
+
+    /// Whether the instruction at `pc` should be logged.
+    pub fn wants(&self, pc: u16) -> bool {
+        self.remaining != Some(0) && self.range.contains(&pc)
+    }
+
+    /// Logs a line for an instruction `wants` said yes to.
+    pub fn write(&mut self, mut line: String) -> Result<(), String> {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                line.push_str("\n# trace limit reached");
+            }
+        }
+        writeln!(self.out, "{}", line)
+            .and_then(|_| match self.remaining {
+                Some(0) => self.out.flush(),
+                _ => Ok(()),
+            })
+            .map_err(|e| format!("Unable to write trace: {}", e))
+    }
+}
+
+/// A line of a text trace, `0206  d015  sprite v0 v1 5  I=0214 VF=01
+/// vf=01`: the address, opcode, disassembly, I and VF after the
+/// instruction, then each register it changed.
+pub fn text_line(
+    pc: u16,
+    op: u16,
+    before: &Snapshot,
+    mem: &ChipMemory,
+    symbols: &Symbols,
+) -> String {
+    let mut line = format!(
+        "{:04x}  {:04x}  {:<26}  I={:04x} VF={:02x}",
+        pc,
+        op,
+        disassemble(op, symbols),
+        mem.i,
+        mem.registers[0xF]
+    );
+    let mut changes = Vec::new();
+    for (n, (old, new)) in before.registers.iter().zip(mem.registers).enumerate() {
+        if *old != new {
+            changes.push(format!("v{:x}={:02x}", n, new));
+        }
+    }
+    if before.i != mem.i {
+        changes.push(format!("i={:04x}", mem.i));
+    }
+    if !changes.is_empty() {
+        line.push_str("  ");
+        line.push_str(&changes.join(" "));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_text_line() {
+        let mut mem = ChipMemory::new();
+        let before = Snapshot::take(&mem);
+        mem.registers[1] = 5;
+        mem.i = 0x214;
+        assert_eq!(
+            text_line(0x202, 0x6105, &before, &mem, &Symbols::default()),
+            "0202  6105  v1 := 0x05                  I=0214 VF=00  v1=05 i=0214"
+        );
+    }
+}
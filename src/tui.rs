@@ -0,0 +1,633 @@
+use crate::audio::AudioBackend;
+use crate::disasm;
+use crate::gdbstub::GdbStub;
+use crate::machine::{ChipMachine, ChipMemory};
+use crate::memview::{self, Mark, MemoryView};
+use crate::movie::{MoviePlayer, MovieRecorder};
+use crate::recorder::Recorder;
+use crate::rom_config::{Button, RomConfig, RomConfigStore};
+use crate::screenshot::{self, Palette};
+use crate::script::Script;
+use crate::sprites::{self, SpriteView};
+use crate::watch::HotReload;
+use crate::watchpoints::Register;
+use crate::WDW_HEIGHT;
+use crate::WDW_WIDTH;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Terminals only report key presses, never releases, so a key is held down
+/// for this many frames after each press and auto-repeat keeps it topped up.
+const KEY_HOLD_FRAMES: u8 = 15;
+
+/// Puts stdin into raw, non-blocking mode for as long as it is alive.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enable() -> Result<Self, String> {
+        // SAFETY: termios is plain old data and both calls only read or write
+        // the struct we hand them.
+        let original = unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err("stdin is not a terminal".to_string());
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err("Unable to put the terminal in raw mode".to_string());
+            }
+            original
+        };
+        // Hide the cursor and clear the screen
+        print!("\x1b[?25l\x1b[2J");
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `enable`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+        print!("\x1b[0m\x1b[?25h\r\n");
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Maps the same keys as the SDL frontend onto the hex keypad.
+fn keypad_index(key: u8) -> Option<usize> {
+    match key.to_ascii_lowercase() {
+        b'x' => Some(0x0),
+        b'1' => Some(0x1),
+        b'2' => Some(0x2),
+        b'3' => Some(0x3),
+        b'q' => Some(0x4),
+        b'w' => Some(0x5),
+        b'e' => Some(0x6),
+        b'a' => Some(0x7),
+        b's' => Some(0x8),
+        b'd' => Some(0x9),
+        b'z' => Some(0xA),
+        b'c' => Some(0xB),
+        b'4' => Some(0xC),
+        b'r' => Some(0xD),
+        b'f' => Some(0xE),
+        b'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+/// A key press read from the terminal.
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// Esc on its own, or Ctrl-C.
+    Quit,
+    Byte(u8),
+    /// An escape sequence for a special key: what follows `ESC [` up to and
+    /// including the final byte, or the one byte after `ESC O`.
+    Sequence(Vec<u8>),
+}
+
+/// Splits what was read from the terminal into key presses.  Alt+key, which
+/// terminals send as ESC and the key, is ignored rather than taken for Esc.
+fn decode_input(bytes: &[u8]) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    let mut bytes = bytes.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        let input = match (byte, bytes.peek()) {
+            // Ctrl-C, since raw mode swallows the signal
+            (0x03, _) => Input::Quit,
+            (0x1B, None | Some(0x1B)) => Input::Quit,
+            (0x1B, Some(b'[')) => {
+                bytes.next();
+                let mut sequence = Vec::new();
+                for b in bytes.by_ref() {
+                    sequence.push(b);
+                    if (0x40..=0x7E).contains(&b) {
+                        break;
+                    }
+                }
+                Input::Sequence(sequence)
+            }
+            // SS3, sent for the arrows in application cursor mode
+            (0x1B, Some(b'O')) => {
+                bytes.next();
+                match bytes.next() {
+                    Some(b) => Input::Sequence(vec![b]),
+                    // Alt+Shift+O
+                    None => continue,
+                }
+            }
+            (0x1B, Some(_)) => {
+                bytes.next();
+                continue;
+            }
+            (byte, _) => Input::Byte(byte),
+        };
+        inputs.push(input);
+    }
+    inputs
+}
+
+/// A debugging view shown in place of the screen.
+enum DebugView {
+    Memory(MemoryView),
+    Sprites(SpriteView),
+}
+
+/// Draws the machine to the terminal with half-block characters, two rows
+/// of pixels to a line, and reads the keypad from raw terminal input.
+pub struct TerminalFrontend {
+    pub machine: ChipMachine,
+    key_hold: [u8; 16],
+    auto_clk: bool,
+    /// Keeps track of the beeper; the terminal bell is what is heard.
+    audio: Box<dyn AudioBackend>,
+    /// Palette and keymap for the loaded ROM.
+    config: RomConfig,
+    store: RomConfigStore,
+    /// Set in watch mode.
+    pub hot_reload: Option<HotReload>,
+    /// Set when a debugger may attach, which then says when to run.
+    pub gdb: Option<GdbStub>,
+    /// Driving the ROM alongside the keyboard, when given.
+    pub script: Option<Script>,
+    /// Set while recording a movie.
+    pub movie: Option<MovieRecorder>,
+    /// Set while replaying a movie, which runs the machine in place of the
+    /// keyboard until it ends.
+    pub player: Option<MoviePlayer>,
+    /// Whether the replay ended where the recording did, once it has.
+    pub replayed: Option<Result<(), String>>,
+    /// Shown in place of the screen while open; the keypad is ignored.
+    view: Option<DebugView>,
+}
+
+impl TerminalFrontend {
+    pub fn new(
+        machine: ChipMachine,
+        audio: Box<dyn AudioBackend>,
+        config: RomConfig,
+        store: RomConfigStore,
+    ) -> Self {
+        Self {
+            machine,
+            key_hold: [0u8; 16],
+            auto_clk: true,
+            audio,
+            config,
+            store,
+            hot_reload: None,
+            gdb: None,
+            script: None,
+            movie: None,
+            player: None,
+            replayed: None,
+            view: None,
+        }
+    }
+
+    pub fn run_loop(&mut self, mut recorder: Option<&mut Recorder>) -> Result<(), String> {
+        let _raw = RawTerminal::enable()?;
+        let frame_time = Duration::from_nanos(1_000_000_000u64 / 60);
+        let mut next_frame = Instant::now();
+        loop {
+            if !self.poll_input()? {
+                break;
+            }
+            if let Some(hot_reload) = &mut self.hot_reload {
+                if let Some(config) = hot_reload.poll(&mut self.machine, &self.store) {
+                    self.config = config;
+                }
+            }
+            if let Some(gdb) = &mut self.gdb {
+                gdb.poll(&mut self.machine)?;
+                self.auto_clk = gdb.running();
+            }
+            if let Some(player) = self.player.as_mut().filter(|p| !p.finished()) {
+                player.run_frame(&mut self.machine)?;
+                if player.finished() {
+                    self.replayed = Some(player.verify(&self.machine));
+                }
+            } else if self.auto_clk {
+                if let Some(movie) = &mut self.movie {
+                    movie.input(&self.machine);
+                }
+                let cycles = self.machine.cycles_per_frame;
+                self.auto_clk = match &mut self.script {
+                    Some(script) => {
+                        script.frame(&mut self.machine)?;
+                        script.run_cycles(&mut self.machine, cycles)?
+                    }
+                    None => self.machine.run_cycles(cycles)?,
+                };
+                if let (false, Some(gdb)) = (self.auto_clk, &mut self.gdb) {
+                    gdb.stopped();
+                }
+            }
+            if let Some(movie) = &mut self.movie {
+                movie.frame(&self.machine);
+            }
+            self.machine.tick_timers();
+            let sound = self.machine.take_sound_update();
+            // Ring for every beep, however short, unless muted
+            let bell = sound.events.iter().any(|e| e.on) & !self.audio.muted();
+            self.audio.apply(&sound);
+            if let Some(recorder) = recorder.as_deref_mut() {
+                recorder.capture(&self.machine.display, &sound);
+            }
+            self.draw(bell)?;
+
+            next_frame += frame_time;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        }
+        self.audio.finish()
+    }
+
+    /// Handles everything typed since the last frame.  Returns false once
+    /// the user asks to quit.
+    fn poll_input(&mut self) -> Result<bool, String> {
+        for (key, hold) in self.key_hold.iter_mut().enumerate() {
+            *hold = hold.saturating_sub(1);
+            self.machine.input[key] = *hold > 0;
+        }
+
+        let mut buf = [0u8; 64];
+        let len = io::stdin().read(&mut buf).map_err(|e| e.to_string())?;
+        for input in decode_input(&buf[..len]) {
+            let byte = match input {
+                Input::Quit => return Ok(false),
+                Input::Byte(byte) => byte,
+                Input::Sequence(sequence) => {
+                    if self.view.is_some() {
+                        self.view_sequence(&sequence);
+                        continue;
+                    }
+                    let button = match &sequence[..] {
+                        // F12
+                        b"24~" => {
+                            screenshot::save_timestamped(
+                                self.machine.display.pixels(),
+                                self.config.palette,
+                            )?;
+                            None
+                        }
+                        b"A" => Some(Button::Up),
+                        b"B" => Some(Button::Down),
+                        b"C" => Some(Button::Right),
+                        b"D" => Some(Button::Left),
+                        _ => None,
+                    };
+                    if let Some(button) = button {
+                        self.press_button(button);
+                    }
+                    continue;
+                }
+            };
+            match byte {
+                b'\t' => self.cycle_view(),
+                b' ' => self.machine.chip_clk()?,
+                b'm' | b'M' => self.auto_clk = !self.auto_clk,
+                b'k' | b'K' => {
+                    let pc = self.machine.mem.pc;
+                    self.machine.toggle_breakpoint(pc);
+                }
+                _ if self.view.is_some() => self.view_key(byte),
+                b'\r' => self.press_button(Button::A),
+                0x7F => self.press_button(Button::B),
+                b'b' | b'B' => {
+                    let muted = !self.audio.muted();
+                    self.audio.set_muted(muted);
+                }
+                _ => {
+                    if let Some(key) = keypad_index(byte) {
+                        self.press_key(key);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Tab goes from the screen to the memory view to the sprite viewer
+    /// and back.
+    fn cycle_view(&mut self) {
+        self.view = match self.view {
+            None => {
+                let mut view = MemoryView::default();
+                view.go_to(self.machine.mem.pc);
+                Some(DebugView::Memory(view))
+            }
+            Some(DebugView::Memory(_)) => Some(DebugView::Sprites(SpriteView::new(&self.machine))),
+            Some(DebugView::Sprites(_)) => None,
+        }
+    }
+
+    /// Handles a key typed while a debug view is open, in place of the
+    /// keypad.
+    fn view_key(&mut self, byte: u8) {
+        let mem = &mut self.machine.mem;
+        match (&mut self.view, byte.to_ascii_lowercase()) {
+            (Some(DebugView::Memory(view)), b'g') => view.toggle_registers(),
+            (Some(DebugView::Memory(view)), b'p') => view.go_to(mem.pc),
+            (Some(DebugView::Memory(view)), b'i') => view.go_to(mem.i),
+            (Some(DebugView::Memory(view)), byte) => {
+                // Only poke values while paused, so the game can't
+                // overwrite them straight away
+                if let (Some(digit), false) = ((byte as char).to_digit(16), self.auto_clk) {
+                    view.type_digit(mem, digit as u8);
+                }
+            }
+            (Some(DebugView::Sprites(view)), b'p') => view.toggle_planes(),
+            (Some(DebugView::Sprites(view)), b'i') => view.address = mem.i % mem.ram.len() as u16,
+            _ => {}
+        }
+    }
+
+    /// Moves around a debug view with the arrow keys and Page Up/Down.
+    fn view_sequence(&mut self, sequence: &[u8]) {
+        match &mut self.view {
+            Some(DebugView::Memory(view)) => {
+                let row = MemoryView::BYTES_PER_ROW as i32;
+                let page = row * MemoryView::ROWS as i32;
+                let delta = match (sequence, view.register()) {
+                    (b"C", _) | (b"B", Some(_)) => 1,
+                    (b"D", _) | (b"A", Some(_)) => -1,
+                    (b"B", None) => row,
+                    (b"A", None) => -row,
+                    (b"6~", None) => page,
+                    (b"5~", None) => -page,
+                    _ => return,
+                };
+                view.move_cursor(delta);
+            }
+            Some(DebugView::Sprites(view)) => match sequence {
+                b"C" => view.move_by(1),
+                b"D" => view.move_by(-1),
+                b"A" => view.change_height(1),
+                b"B" => view.change_height(-1),
+                b"6~" => view.next_found(true),
+                b"5~" => view.next_found(false),
+                _ => {}
+            },
+            None => {}
+        }
+    }
+
+    fn press_key(&mut self, key: usize) {
+        self.key_hold[key] = KEY_HOLD_FRAMES;
+        self.machine.input[key] = true;
+    }
+
+    /// Presses the keypad key the ROM binds to a controller button: the
+    /// arrow keys, Enter for A and Backspace for B.
+    fn press_button(&mut self, button: Button) {
+        if let Some(key) = self.config.keymap.key(button) {
+            self.press_key(key);
+        }
+    }
+
+    /// What the sprite viewer shows beside the sprite: where it is, its
+    /// format, and the sprites found in the ROM around it.
+    fn sprite_panel(&self, view: &SpriteView) -> Vec<String> {
+        let mut panel = vec![
+            format!("Sprite at {:#06x}", view.address),
+            self.machine
+                .symbols
+                .locate(view.address)
+                .unwrap_or_default(),
+            format!("{}, {} bytes", view.format, view.format.size()),
+            String::new(),
+            format!("Found in the ROM: {}", view.found.len()),
+        ];
+        let next = view.found.partition_point(|&(a, _)| a < view.address);
+        let shown = 16 - panel.len();
+        let first = next.saturating_sub(shown / 2);
+        for &(address, format) in view.found.iter().skip(first).take(shown) {
+            let marker = if address == view.address { '>' } else { ' ' };
+            panel.push(format!("{} {:#06x}  {}", marker, address, format));
+        }
+        panel
+    }
+
+    fn draw(&self, bell: bool) -> Result<(), String> {
+        let mem = &self.machine.mem;
+        // The register being edited is shown inverted
+        let editing = match &self.view {
+            Some(DebugView::Memory(view)) => view.register(),
+            _ => None,
+        };
+        let field = |register: Register, text: String| {
+            if editing == Some(register) {
+                format!("\x1b[7m{}\x1b[27m", text)
+            } else {
+                text
+            }
+        };
+        let mut panel = vec![
+            format!("PC  {}", field(Register::Pc, format!("{:#06x}", mem.pc))),
+            format!("I   {}", field(Register::I, format!("{:#06x}", mem.i))),
+            format!("SP  {}", field(Register::Sp, mem.stack_ptr.to_string())),
+            format!(
+                "DT  {}  ST {}",
+                field(Register::Dt, format!("{:02x}", mem.timers.delay)),
+                field(Register::St, format!("{:02x}", mem.timers.sound))
+            ),
+            String::new(),
+        ];
+        for n in (0..16).step_by(2) {
+            let v = |n: u8| field(Register::V(n), format!("{:02x}", mem.registers[n as usize]));
+            panel.push(format!("V{:X}  {}  V{:X} {}", n, v(n), n + 1, v(n + 1)));
+        }
+        panel.push(String::new());
+        let beep = if mem.timers.sound > 0 { "  BEEP" } else { "" };
+        let state = if self.auto_clk {
+            "RUNNING"
+        } else if self.machine.stop_reason().is_some() || self.machine.breakpoints.contains(&mem.pc)
+        {
+            "BREAK"
+        } else {
+            "PAUSED"
+        };
+        panel.push(format!("{}{}", state, beep));
+        if let Some(DebugView::Sprites(view)) = &self.view {
+            panel = self.sprite_panel(view);
+        }
+
+        let mut out = String::from("\x1b[H");
+        out.push('┌');
+        out.push_str(&"─".repeat(WDW_WIDTH as usize));
+        out.push_str("┐\r\n");
+        let colours = colour_escape(self.config.palette);
+        for row in 0..(WDW_HEIGHT / 2) as usize {
+            let line = match &self.view {
+                Some(DebugView::Memory(view)) => {
+                    Some(memory_row(view, mem, view.rows().nth(row).unwrap_or(0)))
+                }
+                Some(DebugView::Sprites(view)) => Some(sprite_row(view, mem, row)),
+                None => None,
+            };
+            if let Some(line) = line {
+                out.push('│');
+                out.push_str(&line);
+                out.push_str("│  ");
+                out.push_str(panel.get(row).map(String::as_str).unwrap_or(""));
+                out.push_str("\x1b[K\r\n");
+                continue;
+            }
+            out.push('│');
+            out.push_str(&colours);
+            for x in 0..WDW_WIDTH as usize {
+                let top = self.machine.display.get_display_at_location(x, row * 2)?;
+                let bottom = self
+                    .machine
+                    .display
+                    .get_display_at_location(x, row * 2 + 1)?;
+                out.push(match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            out.push_str("\x1b[0m│  ");
+            out.push_str(panel.get(row).map(String::as_str).unwrap_or(""));
+            out.push_str("\x1b[K\r\n");
+        }
+        out.push('└');
+        out.push_str(&"─".repeat(WDW_WIDTH as usize));
+        out.push_str("┘\r\n");
+        out.push_str(&disasm::current_instruction(&self.machine));
+        if let Some(reason) = self.machine.stop_reason() {
+            out.push_str(&format!("  [{}]", reason));
+        }
+        out.push_str("\x1b[K\r\n");
+        let stack = self.machine.symbols.call_stack(mem);
+        if stack.len() > 1 {
+            out.push_str("Called from ");
+            out.push_str(&stack[1..].join(" < "));
+        }
+        out.push_str("\x1b[K\r\n");
+        out.push_str(match self.view {
+            Some(DebugView::Memory(_)) => "Tab sprites  Arrows/PgUp/PgDn move  0-F edit when paused  G registers  P/I go to PC/I  Space step  M pause\x1b[K",
+            Some(DebugView::Sprites(_)) => "Tab close  Left/Right move  Up/Down height  PgUp/PgDn found sprites  P planes  I go to I  Space step  M pause\x1b[K",
+            None => "Esc quit  Space step  M pause  K break  B mute  Tab memory  F12 screenshot  Arrows/Enter/Bksp pad\x1b[K",
+        });
+        if bell {
+            out.push('\x07');
+        }
+
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(out.as_bytes())
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A line of the memory view: the address, 16 bytes with the cursor, PC, I
+/// and return addresses highlighted, and the byte at I that many rows down
+/// as sprite pixels.
+fn memory_row(view: &MemoryView, mem: &ChipMemory, address: u16) -> String {
+    let mut out = format!("{:03x} ", address);
+    for n in 0..MemoryView::BYTES_PER_ROW {
+        let at = address + n;
+        let text = format!("{:02x}", mem.ram[at as usize % mem.ram.len()]);
+        match view.mark(mem, at) {
+            Mark::None => out.push_str(&text),
+            Mark::Stack => out.push_str(&format!("\x1b[4m{}\x1b[24m", text)),
+            Mark::I => out.push_str(&format!("\x1b[33m{}\x1b[39m", text)),
+            Mark::Pc => out.push_str(&format!("\x1b[32m{}\x1b[39m", text)),
+            Mark::Cursor => out.push_str(&format!("\x1b[7m{}\x1b[27m", text)),
+        }
+        out.push(' ');
+    }
+    let sprite_row = (address - view.rows().next().unwrap_or(0)) / MemoryView::BYTES_PER_ROW;
+    let byte = mem.ram[(mem.i as usize + sprite_row as usize) % mem.ram.len()];
+    out.push_str(&format!(" {}   ", memview::sprite_row(byte)));
+    out
+}
+
+/// A line of the sprite viewer: a row of the sprite with each pixel two
+/// characters wide, shaded by the planes it lights.
+fn sprite_row(view: &SpriteView, mem: &ChipMemory, row: usize) -> String {
+    let pixels = sprites::decode(&mem.ram, view.address, view.format);
+    let pixels = pixels.get(row).map_or(&[][..], Vec::as_slice);
+    let mut out = String::new();
+    for &pixel in pixels {
+        out.push_str(match pixel {
+            0 => "· ",
+            1 => "██",
+            2 => "▒▒",
+            _ => "▓▓",
+        });
+    }
+    out.push_str(&" ".repeat(WDW_WIDTH as usize - pixels.len() * 2));
+    out
+}
+
+/// Sets the terminal's colours to the palette, lit pixels being drawn as
+/// text.  The default palette leaves the terminal's own colours alone.
+fn colour_escape(palette: Palette) -> String {
+    if palette == Palette::default() {
+        return String::new();
+    }
+    let [fr, fg, fb] = palette.on;
+    let [br, bg, bb] = palette.off;
+    format!(
+        "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+        fr, fg, fb, br, bg, bb
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_keypad_index() {
+        let keys: Vec<_> = b"x123qweasdzc4rfv"
+            .iter()
+            .map(|&b| keypad_index(b))
+            .collect();
+        assert_eq!(keys, (0..16).map(Some).collect::<Vec<_>>());
+        assert_eq!(keypad_index(b'V'), Some(0xF));
+        assert_eq!(keypad_index(b'5'), None);
+        assert_eq!(keypad_index(b' '), None);
+    }
+    #[test]
+    fn test_decode_input() {
+        use Input::*;
+        assert_eq!(decode_input(b"\x1b"), [Quit]);
+        assert_eq!(decode_input(b"q\x03"), [Byte(b'q'), Quit]);
+        assert_eq!(
+            decode_input(b"\x1b[A\x1b[24~w"),
+            [
+                Sequence(b"A".to_vec()),
+                Sequence(b"24~".to_vec()),
+                Byte(b'w')
+            ]
+        );
+        // SS3 arrows, as sent in application cursor mode
+        assert_eq!(
+            decode_input(b"\x1bOB\x1bOD"),
+            [Sequence(b"B".to_vec()), Sequence(b"D".to_vec())]
+        );
+        // Alt+key doesn't quit
+        assert_eq!(decode_input(b"\x1bw\x1b1e"), [Byte(b'e')]);
+        assert_eq!(decode_input(b"\x1bO"), []);
+        // Esc pressed twice before a frame came round
+        assert_eq!(decode_input(b"\x1b\x1b"), [Quit, Quit]);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::asm;
+use crate::machine::{ChipMachine, ChipMemory};
+use crate::rom_config::{RomConfig, RomConfigStore};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the ROM file is looked at.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Notices when a file is rewritten, by polling its modification time and
+/// size.
+pub struct FileWatcher {
+    path: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+    last_poll: Instant,
+    interval: Duration,
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            stamp: stamp(path),
+            last_poll: Instant::now(),
+            interval: POLL_INTERVAL,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Whether the file has changed since the last call that said so.
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < self.interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        let stamp = stamp(&self.path);
+        if stamp.is_none() || stamp == self.stamp {
+            return false;
+        }
+        self.stamp = stamp;
+        true
+    }
+}
+
+/// Sets breakpoints at each location that can still be found, saying which
+/// can't rather than failing the load.
+pub fn set_breakpoints(machine: &mut ChipMachine, locations: &[String]) {
+    for location in locations {
+        if let Err(e) = machine.break_at(location) {
+            println!("{}", e);
+        }
+    }
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Watch mode: reloads the ROM whenever it is reassembled.
+pub struct HotReload {
+    watcher: FileWatcher,
+    /// Keep breakpoints set during the session across reloads, rather than
+    /// going back to the ones given on the command line.
+    keep_breakpoints: bool,
+    /// Labels or addresses, found again after each reload since labels
+    /// move as the source changes.
+    initial_breakpoints: Vec<String>,
+}
+
+impl HotReload {
+    pub fn new(path: &Path, keep_breakpoints: bool, initial_breakpoints: Vec<String>) -> Self {
+        Self {
+            watcher: FileWatcher::new(path),
+            keep_breakpoints,
+            initial_breakpoints,
+        }
+    }
+
+    /// Follows a different ROM from now on.
+    pub fn watch(&mut self, path: &Path) {
+        if self.watcher.path() != path {
+            self.watcher = FileWatcher::new(path);
+        }
+    }
+
+    /// Resets the machine and reloads the ROM if it has changed, returning
+    /// the ROM's settings when it did.  A ROM that can't be loaded, such as
+    /// source with a syntax error, is reported and the machine left running
+    /// what it had; the next change is tried again.
+    pub fn poll(&mut self, machine: &mut ChipMachine, store: &RomConfigStore) -> Option<RomConfig> {
+        if !self.watcher.changed() {
+            return None;
+        }
+        // An assembler that truncates before writing can be caught half way;
+        // the next write will be noticed too
+        if fs::metadata(self.watcher.path()).map_or(true, |m| m.len() == 0) {
+            return None;
+        }
+        let path = self.watcher.path().display().to_string();
+        let checked = asm::read_rom(self.watcher.path()).and_then(|(rom, _)| {
+            let space = ChipMemory::new().ram.len() - ChipMemory::ROM_STARTING_MEMORY_LOCATION;
+            match rom.len() > space {
+                true => Err(format!("Rom is too large ({} bytes)", rom.len())),
+                false => Ok(()),
+            }
+        });
+        if let Err(e) = checked {
+            println!("{} changed, but can't be loaded: {}", path, e);
+            return None;
+        }
+        println!("{} changed, reloading", path);
+        machine.reset();
+        if !self.keep_breakpoints {
+            machine.clear_breakpoints();
+        }
+        let config = match store.load_rom(machine, &path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Unable to reload {}: {}", path, e);
+                return None;
+            }
+        };
+        // Kept breakpoints already include these, found when first loaded
+        if !self.keep_breakpoints {
+            set_breakpoints(machine, &self.initial_breakpoints);
+        }
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_config::RomSettings;
+
+    /// A reloader for `source`, written out as a new file, that looks at it
+    /// on every poll.
+    fn hot_reload(name: &str, source: &str, keep_breakpoints: bool) -> (HotReload, PathBuf) {
+        let path = std::env::temp_dir().join(format!("chipn80-{}-{}.8o", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        let breakpoints = vec!["if v0 == 9".to_string(), "0x202".to_string()];
+        let mut reload = HotReload::new(&path, keep_breakpoints, breakpoints);
+        reload.watcher.interval = Duration::ZERO;
+        (reload, path)
+    }
+
+    fn load(path: &Path, store: &RomConfigStore) -> ChipMachine {
+        let mut machine = ChipMachine::new(ChipMemory::new());
+        store
+            .load_rom(&mut machine, path.to_str().unwrap())
+            .unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_file_watcher() {
+        let path = std::env::temp_dir().join(format!("chipn80-{}.watched", std::process::id()));
+        fs::write(&path, [1]).unwrap();
+        let mut watcher = FileWatcher::new(&path);
+        watcher.interval = Duration::ZERO;
+        assert!(!watcher.changed());
+        fs::write(&path, [1, 2]).unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        // A file that goes away for a moment isn't a change
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+        fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_reload() {
+        let store = RomConfigStore::open(None, None, RomSettings::default());
+        let (mut reload, path) = hot_reload("failed", ": main v0 := 5 jump main", false);
+        let mut machine = load(&path, &store);
+        machine.run_cycles(3).unwrap();
+        let ram = machine.mem.ram;
+        let pc = machine.mem.pc;
+
+        fs::write(&path, ": main v0 :=").unwrap();
+        assert!(reload.poll(&mut machine, &store).is_none());
+        assert_eq!(machine.mem.ram, ram);
+        assert_eq!(machine.mem.pc, pc);
+
+        // Fixing the source reloads it
+        fs::write(&path, ": main v0 := 6 v1 := 7 jump main").unwrap();
+        assert!(reload.poll(&mut machine, &store).is_some());
+        assert_eq!(machine.mem.ram[0x200..0x204], [0x60, 0x06, 0x61, 0x07]);
+        assert_eq!(machine.mem.pc, 0x200);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_breakpoints() {
+        let store = RomConfigStore::open(None, None, RomSettings::default());
+        let (mut reload, path) = hot_reload("keep", ": main v0 := 5 jump main", true);
+        let mut machine = load(&path, &store);
+        set_breakpoints(&mut machine, &reload.initial_breakpoints);
+        machine.break_at("0x200").unwrap();
+        for n in 0..3 {
+            let source = format!(": main v0 := {} v1 := {} jump main", n, n);
+            fs::write(&path, source).unwrap();
+            assert!(reload.poll(&mut machine, &store).is_some());
+            assert_eq!(machine.watches.len(), 1);
+            assert_eq!(machine.breakpoints.len(), 2);
+        }
+        fs::remove_file(&path).unwrap();
+
+        // Without keeping them, those set during the session go
+        let (mut reload, path) = hot_reload("reset", ": main v0 := 5 jump main", false);
+        let mut machine = load(&path, &store);
+        set_breakpoints(&mut machine, &reload.initial_breakpoints);
+        machine.break_at("0x200").unwrap();
+        fs::write(&path, ": main v0 := 1 v1 := 1 jump main").unwrap();
+        assert!(reload.poll(&mut machine, &store).is_some());
+        assert_eq!(machine.watches.len(), 1);
+        assert_eq!(machine.breakpoints.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use crate::machine::ChipMemory;
+use crate::options::parse_address;
+use crate::symbols::Symbols;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// A register a condition or watchpoint can look at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "I" => Some(Register::I),
+            "PC" => Some(Register::Pc),
+            "SP" => Some(Register::Sp),
+            "DT" => Some(Register::Dt),
+            "ST" => Some(Register::St),
+            upper => match upper.strip_prefix('V') {
+                Some(digit) if digit.len() == 1 => {
+                    u8::from_str_radix(digit, 16).ok().map(Register::V)
+                }
+                _ => None,
+            },
+        }
+    }
+    pub fn value(self, mem: &ChipMemory) -> u16 {
+        match self {
+            Register::V(n) => mem.registers[n as usize] as u16,
+            Register::I => mem.i,
+            Register::Pc => mem.pc,
+            Register::Sp => mem.stack_ptr as u16,
+            Register::Dt => mem.timers.delay as u16,
+            Register::St => mem.timers.sound as u16,
+        }
+    }
+    /// Sets the register, cutting `value` down to what it can hold.
+    pub fn set(self, mem: &mut ChipMemory, value: u16) {
+        match self {
+            Register::V(n) => mem.registers[n as usize] = value as u8,
+            Register::I => mem.i = value,
+            Register::Pc => mem.pc = value & 0xFFF,
+            Register::Sp => mem.stack_ptr = (value as usize).min(mem.stack.len()),
+            Register::Dt => mem.timers.delay = value as u8,
+            Register::St => mem.timers.sound = value as u8,
+        }
+    }
+    /// How many hex digits the register holds.
+    pub fn digits(self) -> u8 {
+        match self {
+            Register::I => 4,
+            Register::Pc => 3,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(n) => write!(f, "V{:X}", n),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl Operator {
+    /// Operators at each precedence level, loosest binding first.
+    const LEVELS: [&'static [(&'static str, Operator)]; 6] = [
+        &[("||", Operator::Or)],
+        &[("&&", Operator::And)],
+        &[
+            ("==", Operator::Eq),
+            ("!=", Operator::Ne),
+            ("<=", Operator::Le),
+            (">=", Operator::Ge),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ],
+        &[("|", Operator::BitOr)],
+        &[("&", Operator::BitAnd)],
+        &[("+", Operator::Add), ("-", Operator::Sub)],
+    ];
+
+    fn apply(self, a: i64, b: i64) -> i64 {
+        match self {
+            Operator::Or => (a != 0 || b != 0) as i64,
+            Operator::And => (a != 0 && b != 0) as i64,
+            Operator::Eq => (a == b) as i64,
+            Operator::Ne => (a != b) as i64,
+            Operator::Lt => (a < b) as i64,
+            Operator::Le => (a <= b) as i64,
+            Operator::Gt => (a > b) as i64,
+            Operator::Ge => (a >= b) as i64,
+            Operator::BitOr => a | b,
+            Operator::BitAnd => a & b,
+            Operator::Add => a.wrapping_add(b),
+            Operator::Sub => a.wrapping_sub(b),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Register(Register),
+    /// The byte of RAM at an address.
+    Ram(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, mem: &ChipMemory) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(register) => register.value(mem) as i64,
+            Expr::Ram(address) => {
+                mem.ram[address.eval(mem).rem_euclid(mem.ram.len() as i64) as usize] as i64
+            }
+            Expr::Not(e) => (e.eval(mem) == 0) as i64,
+            Expr::Binary(Operator::And, a, b) => (a.eval(mem) != 0 && b.eval(mem) != 0) as i64,
+            Expr::Binary(Operator::Or, a, b) => (a.eval(mem) != 0 || b.eval(mem) != 0) as i64,
+            Expr::Binary(op, a, b) => op.apply(a.eval(mem), b.eval(mem)),
+        }
+    }
+}
+
+/// An expression over the machine's registers and RAM, like
+/// `V3 == 0x10 && I > 0x300`.  `[ADDR]` is the byte at ADDR, numbers are
+/// decimal unless written `0x`, and labels stand for their address.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            symbols,
+        };
+        let expr = parser.expr(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("Unexpected {} in {}", token, text));
+        }
+        Ok(Self {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    pub fn holds(&self, mem: &ChipMemory) -> bool {
+        self.expr.eval(mem) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Splits a condition into words, numbers and operators.
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+            {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else if "=!<>&|".contains(c) {
+            chars.next();
+            let pair: String = [c, chars.peek().copied().unwrap_or(' ')].iter().collect();
+            if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()) {
+                chars.next();
+                tokens.push(pair);
+            } else if c == '=' {
+                return Err("Use == to compare".to_string());
+            } else {
+                tokens.push(c.to_string());
+            }
+        } else if "+-()[]".contains(c) {
+            chars.next();
+            tokens.push(c.to_string());
+        } else {
+            return Err(format!("Unexpected {} in {}", c, text));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("Condition ends too soon")?;
+        self.pos += 1;
+        Ok(token)
+    }
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.next()? {
+            t if t == token => Ok(()),
+            t => Err(format!("Expected {}, not {}", token, t)),
+        }
+    }
+
+    /// Operators from precedence `level` down, left to right.
+    fn expr(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = Operator::LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.expr(level + 1)?;
+        while let Some(&(_, op)) = self
+            .tokens
+            .get(self.pos)
+            .and_then(|t| operators.iter().find(|(text, _)| text == t))
+        {
+            self.pos += 1;
+            let right = self.expr(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        match token.as_str() {
+            "!" => Ok(Expr::Not(Box::new(self.unary()?))),
+            "-" => Ok(Expr::Binary(
+                Operator::Sub,
+                Box::new(Expr::Number(0)),
+                Box::new(self.unary()?),
+            )),
+            "(" => {
+                let e = self.expr(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            "[" => {
+                let e = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::Ram(Box::new(e)))
+            }
+            word if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                let number = match word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                number
+                    .map(Expr::Number)
+                    .map_err(|_| format!("Invalid number {}", word))
+            }
+            word => {
+                if let Some(register) = Register::parse(word) {
+                    Ok(Expr::Register(register))
+                } else if let Some(address) = self.symbols.address(word) {
+                    Ok(Expr::Number(address as i64))
+                } else {
+                    Err(format!("No register or label {}", word))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A byte of RAM an instruction read or wrote.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+/// A reason to stop other than reaching an address.
+#[derive(Clone, Debug)]
+pub enum Watch {
+    /// Stops before the instruction at an address when the condition holds.
+    At(u16, Condition),
+    /// Stops before the next instruction once the condition becomes true.
+    When { condition: Condition, held: bool },
+    /// Stops after an instruction reads or writes a byte in the range.
+    Memory {
+        range: RangeInclusive<u16>,
+        access: Access,
+    },
+    /// Stops after an instruction changes the register.
+    Change(Register),
+}
+
+impl Watch {
+    /// Reads a watchpoint as the debugger's `--break` takes it:
+    ///
+    /// - `LOCATION if CONDITION`, a breakpoint that only stops when the
+    ///   condition holds
+    /// - `if CONDITION`, stopping wherever the condition becomes true
+    /// - `read ADDR[-ADDR]` or `write ADDR[-ADDR]`, on RAM accesses
+    /// - `change REGISTER`, when V0 to VF, I, SP, DT or ST changes
+    ///
+    /// Anything else is a plain breakpoint, and `None` is returned.
+    pub fn parse(spec: &str, symbols: &Symbols) -> Result<Option<Self>, String> {
+        let spec = spec.trim();
+        let (word, rest) = spec.split_once(char::is_whitespace).unwrap_or((spec, ""));
+        let rest = rest.trim();
+        let watch = match word.to_ascii_lowercase().as_str() {
+            "if" => Watch::When {
+                condition: Condition::parse(rest, symbols)?,
+                held: false,
+            },
+            "read" | "write" => Watch::Memory {
+                range: parse_range(rest, symbols)?,
+                access: if word.eq_ignore_ascii_case("read") {
+                    Access::Read
+                } else {
+                    Access::Write
+                },
+            },
+            "change" => match Register::parse(rest) {
+                Some(Register::Pc) => return Err("PC changes on every instruction".to_string()),
+                Some(register) => Watch::Change(register),
+                None => return Err(format!("No register {}", rest)),
+            },
+            _ => match rest.split_once(char::is_whitespace) {
+                Some((keyword, condition)) if keyword.eq_ignore_ascii_case("if") => Watch::At(
+                    locate(word, symbols)?,
+                    Condition::parse(condition, symbols)?,
+                ),
+                _ if rest.is_empty() => return Ok(None),
+                _ => return Err(format!("Expected LOCATION if CONDITION, not {}", spec)),
+            },
+        };
+        Ok(Some(watch))
+    }
+}
+
+/// A label or hex address.  Labels are looked for first, since names like
+/// `face` are also hex.
+pub fn locate(location: &str, symbols: &Symbols) -> Result<u16, String> {
+    match symbols.address(location) {
+        Some(address) => Ok(address),
+        None => parse_address(location).map_err(|_| format!("No label or address {}", location)),
+    }
+}
+
+fn parse_range(text: &str, symbols: &Symbols) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    let (start, end) = (locate(start.trim(), symbols)?, locate(end.trim(), symbols)?);
+    if end < start {
+        return Err(format!("Empty address range {}", text));
+    }
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_conditions() {
+        let symbols = Symbols::parse("0x300 buffer\n").unwrap();
+        let mut mem = ChipMemory::new();
+        mem.registers[3] = 0x10;
+        mem.i = 0x301;
+        mem.ram[0x301] = 7;
+        let holds = |text: &str| Condition::parse(text, &symbols).unwrap().holds(&mem);
+        assert!(holds("V3 == 0x10 && I > 0x300"));
+        assert!(holds("v3==16&&i>buffer"));
+        assert!(!holds("V3 == 0x10 && (I < 0x300 || VF)"));
+        assert!(holds("[I] == 7 && [buffer + 1] - 7 == 0"));
+        assert!(holds("!V0 && V3 & 0x10"));
+        assert!(holds("0x7fffffffffffffff + 1 < 0"));
+        assert!(holds("0 - 0x7fffffffffffffff - 2 > 0"));
+        assert!(Condition::parse("V3 = 1", &symbols).is_err());
+        assert!(Condition::parse("V3 == (1", &symbols).is_err());
+        assert!(Condition::parse("VG == 1", &symbols).is_err());
+
+        assert!(matches!(
+            Watch::parse("write 3a0-3a2", &symbols),
+            Ok(Some(Watch::Memory { range, access: Access::Write })) if range == (0x3A0..=0x3A2)
+        ));
+        assert!(matches!(
+            Watch::parse("buffer if VF == 1", &symbols),
+            Ok(Some(Watch::At(0x300, _)))
+        ));
+        assert!(matches!(
+            Watch::parse("change vf", &symbols),
+            Ok(Some(Watch::Change(Register::V(0xF))))
+        ));
+        assert!(matches!(Watch::parse("buffer", &symbols), Ok(None)));
+    }
+}
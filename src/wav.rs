@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::Path;
+
+/// Collects mono samples in memory and writes them out as a 16-bit PCM WAV.
+pub struct WavWriter {
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl WavWriter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            samples: Vec::new(),
+        }
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    pub fn push(&mut self, sample: f32) {
+        self.samples
+            .push((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+    }
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let data_len = (self.samples.len() * 2) as u32;
+        let mut out = Vec::with_capacity(44 + data_len as usize);
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(36 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, one channel
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&self.sample_rate.to_le_bytes());
+        out.extend_from_slice(&(self.sample_rate * 2).to_le_bytes());
+        out.extend_from_slice(&2u16.to_le_bytes());
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for sample in &self.samples {
+            out.extend_from_slice(&sample.to_le_bytes());
+        }
+        out
+    }
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.encode())
+            .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+}